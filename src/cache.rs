@@ -1 +1,229 @@
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{BuildHasher, RandomState};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
 
+use crate::dbfile::{EntryHandle, FileId};
+
+const NUM_SHARDS: usize = 16;
+
+/// The bytes charged for a cached value besides the value itself.
+const ENTRY_OVERHEAD: u64 = 64;
+
+/// Counters of the row cache, see `BitcaskDB::row_cache_stats`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// the bytes charged by the cached values.
+    pub usage: u64,
+    pub capacity: u64,
+}
+
+/// A byte-bounded LRU of values keyed by the handle of their record.
+/// A handle is never reused for another record, so an entry only goes
+/// stale when its file is deleted.
+///
+/// The cache is split into shards by the hash of the handle, each one
+/// has its own lock and an even part of the capacity.
+pub(crate) struct RowCache {
+    shards: Vec<Mutex<LruShard>>,
+    hasher: RandomState,
+    capacity: u64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Default)]
+struct LruShard {
+    capacity: u64,
+    usage: u64,
+    /// handle -> (value, last use).
+    entries: HashMap<EntryHandle, (Vec<u8>, u64)>,
+    /// last use -> handle, the first one is the least recently used.
+    lru: BTreeMap<u64, EntryHandle>,
+    tick: u64,
+}
+
+impl LruShard {
+    fn charge(value: &[u8]) -> u64 {
+        value.len() as u64 + ENTRY_OVERHEAD
+    }
+
+    fn get(&mut self, handle: &EntryHandle) -> Option<Vec<u8>> {
+        self.tick += 1;
+        let (value, last_use) = self.entries.get_mut(handle)?;
+        self.lru.remove(last_use);
+        self.lru.insert(self.tick, *handle);
+        *last_use = self.tick;
+        Some(value.clone())
+    }
+
+    fn insert(&mut self, handle: EntryHandle, value: Vec<u8>) {
+        let charge = Self::charge(&value);
+        if charge > self.capacity {
+            return;
+        }
+        self.remove(&handle);
+        while self.usage + charge > self.capacity {
+            let (_, oldest) = self.lru.pop_first().expect("usage without entries");
+            let (value, _) = self.entries.remove(&oldest).unwrap();
+            self.usage -= Self::charge(&value);
+        }
+        self.tick += 1;
+        self.lru.insert(self.tick, handle);
+        self.entries.insert(handle, (value, self.tick));
+        self.usage += charge;
+    }
+
+    fn remove(&mut self, handle: &EntryHandle) {
+        if let Some((value, last_use)) = self.entries.remove(handle) {
+            self.lru.remove(&last_use);
+            self.usage -= Self::charge(&value);
+        }
+    }
+
+    fn erase_file(&mut self, file_id: FileId) {
+        let handles: Vec<_> = self
+            .entries
+            .keys()
+            .filter(|x| x.file_id == file_id)
+            .copied()
+            .collect();
+        for handle in &handles {
+            self.remove(handle);
+        }
+    }
+}
+
+impl RowCache {
+    /// A cache holding up to `capacity` bytes, 0 disables it.
+    pub(crate) fn new(capacity: u64) -> Self {
+        let shard_capacity = capacity / NUM_SHARDS as u64;
+        Self {
+            shards: (0..NUM_SHARDS)
+                .map(|_| {
+                    Mutex::new(LruShard {
+                        capacity: shard_capacity,
+                        ..Default::default()
+                    })
+                })
+                .collect(),
+            hasher: RandomState::new(),
+            capacity,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    fn shard(&self, handle: &EntryHandle) -> &Mutex<LruShard> {
+        let hash = self.hasher.hash_one(handle) as usize;
+        &self.shards[hash % self.shards.len()]
+    }
+
+    pub(crate) fn get(&self, handle: &EntryHandle) -> Option<Vec<u8>> {
+        if !self.is_enabled() {
+            return None;
+        }
+        let value = self.shard(handle).lock().unwrap().get(handle);
+        let counter = if value.is_some() {
+            &self.hits
+        } else {
+            &self.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        value
+    }
+
+    pub(crate) fn insert(&self, handle: EntryHandle, value: Vec<u8>) {
+        if self.is_enabled() {
+            self.shard(&handle).lock().unwrap().insert(handle, value);
+        }
+    }
+
+    /// Drop all the values read from `file_id`, called once the file is
+    /// deleted.
+    pub(crate) fn erase_file(&self, file_id: FileId) {
+        if self.is_enabled() {
+            for shard in &self.shards {
+                shard.lock().unwrap().erase_file(file_id);
+            }
+        }
+    }
+
+    pub(crate) fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            usage: self.shards.iter().map(|x| x.lock().unwrap().usage).sum(),
+            capacity: self.capacity,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RowCache, ENTRY_OVERHEAD, NUM_SHARDS};
+    use crate::dbfile::EntryHandle;
+
+    fn handle(file_id: u64, offset: u64) -> EntryHandle {
+        EntryHandle {
+            file_id,
+            offset,
+            length: 1,
+        }
+    }
+
+    #[test]
+    fn test_lru_eviction() {
+        // two values of 36 bytes fit in one shard, a third one does not
+        let cache = RowCache::new(NUM_SHARDS as u64 * (ENTRY_OVERHEAD + 36) * 2);
+        let shard = cache.shard(&handle(1, 0));
+        let mut same_shard = (0..)
+            .map(|x| handle(1, x))
+            .filter(|x| std::ptr::eq(cache.shard(x), shard));
+        let (a, b, c) = (
+            same_shard.next().unwrap(),
+            same_shard.next().unwrap(),
+            same_shard.next().unwrap(),
+        );
+
+        cache.insert(a, vec![b'a'; 36]);
+        cache.insert(b, vec![b'b'; 36]);
+        assert_eq!(cache.get(&a), Some(vec![b'a'; 36]));
+        // b is the least recently used one now
+        cache.insert(c, vec![b'c'; 36]);
+        assert_eq!(cache.get(&b), None);
+        assert_eq!(cache.get(&a), Some(vec![b'a'; 36]));
+        assert_eq!(cache.get(&c), Some(vec![b'c'; 36]));
+
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses), (3, 1));
+        assert_eq!(stats.usage, (ENTRY_OVERHEAD + 36) * 2);
+
+        // too large for a shard
+        cache.insert(b, vec![b'b'; 1024]);
+        assert_eq!(cache.get(&b), None);
+    }
+
+    #[test]
+    fn test_erase_file_and_disabled() {
+        let cache = RowCache::new(1 << 20);
+        for i in 0..100 {
+            cache.insert(handle(1 + i % 2, i), vec![0; 10]);
+        }
+        cache.erase_file(1);
+        assert!((0..100).all(|i| cache.get(&handle(1, i)).is_none()));
+        assert_eq!(cache.get(&handle(2, 1)), Some(vec![0; 10]));
+        assert_eq!(cache.stats().usage, 50 * (ENTRY_OVERHEAD + 10));
+
+        let cache = RowCache::new(0);
+        cache.insert(handle(1, 0), vec![0; 10]);
+        assert_eq!(cache.get(&handle(1, 0)), None);
+        assert_eq!(cache.stats().misses, 0);
+    }
+}
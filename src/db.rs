@@ -1,96 +1,1140 @@
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::ops::{Bound, RangeBounds};
 use std::path::{Path, PathBuf};
-use std::rc::Rc;
-use std::sync::{Arc, Mutex};
+use std::ptr::NonNull;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, RwLock, Weak};
+use std::time::Duration;
 
-use crate::dbfile::{EntryBlock, EntryHandle, FileId, KeyAndEntryHandle, LogFile, INVALID_FILE_ID};
-use crate::errors::{from_io_error, DBError, DBResult};
+use im::OrdMap;
+
+use crate::cache::{CacheStats, RowCache};
+use crate::dbfile::{
+    EntryHandle, FileId, IndexEntry, KeyAndEntryHandle, LogFile, LogRecord, INVALID_FILE_ID,
+};
+use crate::env::FileLock;
+use crate::errors::{
+    corruption, corruption_at, from_io_error, internal, invalid_argument, io_error, DBError,
+    DBResult, ErrorKind,
+};
 use crate::filename::FileType;
-use crate::model::OpType;
-use crate::options::{Options, ReadOptions, WriteOptions};
+use crate::hint::{self, HintEntry};
+use crate::iterator::DBIterator;
+use crate::model::{now_millis, OpType, OwnedEntry, ValueMeta};
+use crate::options::{CorruptionPolicy, Options, ReadOptions, SyncPolicy, WriteOptions};
+use crate::snapshot::Snapshot;
 use crate::versionset::{VersionEdit, VersionSet};
 use crate::writebatch::WriteBatch;
 
 pub struct BitcaskDB {
     options: Arc<Options>,
     core: Arc<Mutex<BitcaskCore>>,
+    /// shared with the core, `get` only takes this lock.
+    read_state: Arc<RwLock<ReadState>>,
+    row_cache: Arc<RowCache>,
+    /// signaled when a group write finishes, see `BitcaskDB::write`.
+    writers_cv: Condvar,
+    /// running under `SyncPolicy::EveryInterval`.
+    sync_thread: Option<SyncThread>,
+    /// `close` was called, `drop` has nothing left to do.
+    closed: bool,
+}
+
+/// Counters of the writes to the log and their syncs, see
+/// `BitcaskDB::sync_stats`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SyncStats {
+    /// the writes appended to the log.
+    pub writes: u64,
+    pub bytes_written: u64,
+    /// the syncs which made appended writes durable.
+    pub syncs: u64,
+    /// the writes and bytes appended since the last sync, which a crash
+    /// of the machine may lose.
+    pub unsynced_writes: u64,
+    pub unsynced_bytes: u64,
+}
+
+/// The thread syncing the active file under `SyncPolicy::EveryInterval`,
+/// it is stopped and joined when dropped.
+struct SyncThread {
+    /// nothing is sent, dropping it stops the thread.
+    stop: Option<mpsc::Sender<()>>,
+    handle: Option<std::thread::JoinHandle<()>>,
+}
+
+impl SyncThread {
+    fn spawn(core: Weak<Mutex<BitcaskCore>>, interval: Duration) -> SyncThread {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let handle = std::thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                match core.upgrade() {
+                    Some(core) => sync_active_file(&core),
+                    None => break,
+                }
+            }
+        });
+        SyncThread {
+            stop: Some(stop),
+            handle: Some(handle),
+        }
+    }
+}
+
+impl Drop for SyncThread {
+    fn drop(&mut self) {
+        self.stop.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Sync the writes appended to the active file so far, if there are any.
+/// The sync runs without the core lock.
+fn sync_active_file(core: &Mutex<BitcaskCore>) {
+    let (file, writes, bytes) = {
+        let mut core = core.lock().unwrap();
+        let file = match core.active_file.clone() {
+            Some(file) if core.sync_stats.unsynced_writes > 0 => file,
+            _ => return,
+        };
+        let stats = &mut core.sync_stats;
+        let writes = std::mem::take(&mut stats.unsynced_writes);
+        let bytes = std::mem::take(&mut stats.unsynced_bytes);
+        (file, writes, bytes)
+    };
+    let result = file.sync();
+    let mut core = core.lock().unwrap();
+    match result {
+        Ok(()) => core.sync_stats.syncs += 1,
+        Err(e) => {
+            core.sync_stats.unsynced_writes += writes;
+            core.sync_stats.unsynced_bytes += bytes;
+            core.set_bg_error(e);
+        }
+    }
+}
+
+/// The state a read needs, it has its own lock so that a read never
+/// waits for the file io done under the core lock. The core lock is
+/// always taken before this one.
+///
+/// A snapshot is a cheap clone of both parts. The index is a persistent
+/// map, a change after a snapshot only copies the path to the changed
+/// key. The files are copied on write, there are few of them.
+#[derive(Default, Clone)]
+pub(crate) struct ReadState {
+    /// the latest put of each key, ordered by key for iterators. A
+    /// deletion removes the key. A put with a TTL stays after it expires,
+    /// reads and iterators skip it, and a compaction drops it.
+    pub(crate) mem_index: OrdMap<Vec<u8>, IndexEntry>,
+
+    /// the active and freeze files, every handle in `mem_index` points
+    /// into one of them.
+    files: Arc<HashMap<FileId, Arc<LogFile>>>,
+}
+
+/// What a record still means to the index, see `ReadState::liveness`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Liveness {
+    /// the put the index points to.
+    Live,
+    /// a delete, or the put of an expired key, which has to keep hiding
+    /// the key in older files.
+    Tombstone,
+    /// overwritten by a newer record.
+    Dead,
+}
+
+impl ReadState {
+    /// The file holding the record of an index entry. The `Arc` keeps
+    /// the file alive even if a compaction retires it afterwards.
+    pub(crate) fn locate(&self, handle: &EntryHandle) -> (EntryHandle, Arc<LogFile>) {
+        assert!(handle.file_id != INVALID_FILE_ID);
+        let file = self
+            .files
+            .get(&handle.file_id)
+            .cloned()
+            .expect("the index of key points to a non-exist place");
+        (*handle, file)
+    }
+
+    /// The live entry of `key`, none if it is missing or expired.
+    pub(crate) fn lookup(&self, key: &[u8], now: u64) -> Option<&IndexEntry> {
+        self.mem_index.get(key).filter(|x| !x.is_expired(now))
+    }
+
+    fn apply(&mut self, op_type: OpType, key: Vec<u8>, entry: IndexEntry) {
+        match op_type {
+            OpType::Put => {
+                self.mem_index.insert(key, entry);
+            }
+            OpType::Del => {
+                self.mem_index.remove(&key);
+            }
+        }
+    }
+
+    /// Apply a record found by recovery, of two records of a key the one
+    /// with the larger sequence wins whatever order they are replayed in.
+    /// `deleted` keeps the sequences of the deletions seen so far.
+    fn recover(
+        &mut self,
+        op_type: OpType,
+        key: Vec<u8>,
+        entry: IndexEntry,
+        deleted: &mut HashMap<Vec<u8>, u64>,
+    ) {
+        let newest = self
+            .mem_index
+            .get(&key)
+            .map(|x| x.seq)
+            .max(deleted.get(&key).copied());
+        if newest.is_some_and(|x| x > entry.seq) {
+            return;
+        }
+        match op_type {
+            OpType::Put => {
+                deleted.remove(&key);
+            }
+            OpType::Del => {
+                deleted.insert(key.clone(), entry.seq);
+            }
+        }
+        self.apply(op_type, key, entry);
+    }
+
+    fn files_mut(&mut self) -> &mut HashMap<FileId, Arc<LogFile>> {
+        Arc::make_mut(&mut self.files)
+    }
+
+    /// Whether a record of `key` at `handle` is still needed. A delete
+    /// matters as long as the key has no live put, so does the put of an
+    /// expired key, dropping it could bring back an older put.
+    fn liveness(&self, op_type: OpType, key: &[u8], handle: &EntryHandle, now: u64) -> Liveness {
+        let current = self.mem_index.get(key);
+        match op_type {
+            OpType::Put => match current {
+                Some(x) if x.handle == *handle && x.is_expired(now) => Liveness::Tombstone,
+                Some(x) if x.handle == *handle => Liveness::Live,
+                _ => Liveness::Dead,
+            },
+            OpType::Del => match current {
+                Some(x) if !x.is_expired(now) => Liveness::Dead,
+                _ => Liveness::Tombstone,
+            },
+        }
+    }
 }
 
 struct BitcaskCore {
     /// the only file active for accept write.
-    active_file: Option<Rc<LogFile>>,
+    active_file: Option<Arc<LogFile>>,
+    /// the latest record of each key in the active file, collected as
+    /// they are appended so that its hint is written without reading the
+    /// file back.
+    active_hint: BTreeMap<Vec<u8>, HintEntry>,
 
     /// all freeze files mappings, contains log or rewrite log.
-    freeze_files: HashMap<FileId, Rc<LogFile>>,
+    freeze_files: HashMap<FileId, Arc<LogFile>>,
 
-    /// the in-memory index parts, maybe hashmap/btreemap/artree
-    mem_index: BTreeMap<Vec<u8>, EntryHandle>,
+    /// the index and the files readers look up, see `ReadState`.
+    read_state: Arc<RwLock<ReadState>>,
 
-    /// TODO: LRU
-    row_cache: HashMap<EntryHandle, EntryBlock>,
+    /// values of recent reads, the ones of a file are dropped when the
+    /// file is deleted.
+    row_cache: Arc<RowCache>,
 
     path: PathBuf,
-    bg_error: Option<DBError>,
+    options: Arc<Options>,
+    /// the first failure which may have left the files or the manifest
+    /// behind the memory state, writes fail with it until `resume`.
+    bg_error: Option<Arc<DBError>>,
+    /// where the active file is cut by `resume`, the start of the first
+    /// group write which failed.
+    failed_write_offset: Option<u64>,
     version_set: VersionSet,
+    compaction_running: bool,
+
+    /// files merged by a compaction, deleted once no reader uses them.
+    obsolete_files: Vec<Arc<LogFile>>,
+
+    /// holds the advisory lock on LOCK, it is released when dropped.
+    lock_file: Option<Box<dyn FileLock>>,
+
+    /// writes waiting for their turn, the first one is the leader which
+    /// writes a group of them, see `BitcaskDB::write`.
+    writers: VecDeque<Writer>,
+    next_writer_id: u64,
+    /// results of the writers written by another leader.
+    write_results: HashMap<u64, DBResult<()>>,
+    /// a leader is appending to the active file without the core lock,
+    /// the active file must not be swapped meanwhile.
+    logging: bool,
+    sync_stats: SyncStats,
+}
+
+/// A `write` waiting in the writer queue.
+struct Writer {
+    id: u64,
+    /// the batch passed to `write`, which is not copied: the writer stays
+    /// in `write` until the leader hands it the result, and the leader is
+    /// done with the batch by then.
+    batch: NonNull<WriteBatch>,
+    sync: bool,
+}
+
+// SAFETY: `batch` points to a `WriteBatch` borrowed by the `write` call
+// which queued the writer. Another thread only reads it as the leader,
+// under the core lock, while that call is blocked in `write`: it returns
+// only once the leader drained the writer from the queue and stored its
+// result in `write_results`, after the last read of the batch. So the
+// pointee outlives every access, and it is only shared, `WriteBatch` is
+// `Sync`. If the blocked call panics instead, the core lock is poisoned
+// and no leader runs again.
+unsafe impl Send for Writer {}
+
+impl Writer {
+    fn batch(&self) -> &WriteBatch {
+        // SAFETY: the writer is in the queue, so the `write` call owning
+        // the batch has not returned yet, see `unsafe impl Send`.
+        unsafe { self.batch.as_ref() }
+    }
+}
+
+/// The batches of a group write encoded by the leader.
+struct WriteGroup {
+    log: Arc<LogFile>,
+    data: Vec<u8>,
+    /// the offsets are relative to `data`.
+    handles: Vec<KeyAndEntryHandle>,
+}
+
+/// A group write stops taking batches once it holds this many bytes.
+const MAX_GROUP_SIZE: usize = 1 << 20;
+
+/// A small leading batch only takes this many more bytes along, so that
+/// the small write is not slowed down much.
+const SMALL_GROUP_GROWTH: usize = 128 << 10;
+
+/// The number of records checked against `mem_index` under one lock
+/// while merging, the lock is released between batches.
+const COMPACTION_BATCH_SIZE: usize = 256;
+
+/// The state of a merge running outside of the core lock.
+struct Compaction {
+    dbpath: PathBuf,
+    /// freeze files to merge, in the order of their file ids.
+    inputs: Vec<Arc<LogFile>>,
+    /// ids reserved for the rewrite files, all less than the id of the
+    /// active file so that recovery replays them before any newer write.
+    output_ids: Vec<FileId>,
+    outputs: Vec<Arc<LogFile>>,
+    /// tombstones in input files newer than this one must be kept, they
+    /// still hide the keys in older files which are not merged.
+    keep_tombstones_after: FileId,
+    /// key, handle in the input file, handle in the output file.
+    moved: Vec<(Vec<u8>, EntryHandle, EntryHandle)>,
+    /// the records copied to each output, for their hints.
+    output_hints: HashMap<FileId, Vec<HintEntry>>,
+    /// expired puts which are not copied, their keys leave the index.
+    dropped: Vec<(Vec<u8>, EntryHandle)>,
 }
 
 impl BitcaskCore {
-    fn new(dbpath: PathBuf) -> Self {
+    fn new(dbpath: PathBuf, options: Arc<Options>) -> Self {
         Self {
             active_file: None,
+            active_hint: BTreeMap::new(),
             freeze_files: HashMap::new(),
-            mem_index: BTreeMap::new(),
-            row_cache: HashMap::new(),
+            read_state: Arc::new(RwLock::new(ReadState::default())),
+            row_cache: Arc::new(RowCache::new(options.row_cache_size)),
             bg_error: None,
-            version_set: VersionSet::new(dbpath.clone()),
+            failed_write_offset: None,
+            version_set: VersionSet::new(dbpath.clone(), options.clone()),
             path: dbpath.clone(),
+            options,
+            compaction_running: false,
+            obsolete_files: vec![],
+            lock_file: None,
+            writers: VecDeque::new(),
+            next_writer_id: 0,
+            write_results: HashMap::new(),
+            logging: false,
+            sync_stats: SyncStats::default(),
         }
     }
 
-    fn prepare_new_active_file(&mut self) -> DBResult<Rc<LogFile>> {
+    fn prepare_new_active_file(&mut self) -> DBResult<Arc<LogFile>> {
+        // whatever the sync policy, a frozen file is complete on disk: the
+        // manifest and its hint tell recovery so.
+        if let Some(old_active_file) = &self.active_file {
+            if let Err(e) = old_active_file.sync() {
+                return Err(self.set_bg_error(e));
+            }
+            if self.sync_stats.unsynced_writes > 0 {
+                self.record_sync();
+            }
+        }
         let new_log_id = self.version_set.new_logfile_id();
         let new_log_path = FileType::Log.get_full_filepath(self.path.clone(), new_log_id);
-        let file = std::fs::File::options()
-            .append(true)
-            .create(true)
-            .read(true)
-            .write(true)
-            .open(new_log_path)
-            .map_err(|e| from_io_error(e))?;
-        let active_file = Rc::new(LogFile::new(new_log_id, file));
-        let mut edit = VersionEdit::default();
-        edit.new_active_file = Some(new_log_id);
-        edit.need_freeze = self.active_file.as_ref().map(|x| x.get_file_id());
-        self.version_set.log_and_apply(&edit)?;
+        let active_file = Arc::new(LogFile::create(
+            self.options.env.as_ref(),
+            new_log_id,
+            new_log_path,
+        )?);
+        // the file must outlive a crash once the manifest refers to it.
+        self.options
+            .env
+            .sync_dir(&self.path)
+            .map_err(from_io_error)?;
+        let mut edit = VersionEdit {
+            new_active_file: Some(new_log_id),
+            need_freeze: self.active_file.as_ref().map(|x| x.get_file_id()),
+            ..Default::default()
+        };
+        self.log_and_apply(&mut edit)?;
 
         // change the memory state which is a not-fail operation.
+        self.read_state
+            .write()
+            .unwrap()
+            .files_mut()
+            .insert(new_log_id, active_file.clone());
         let old_active_file = self.active_file.replace(active_file.clone());
-        if old_active_file.is_some() {
+        let old_hint = std::mem::take(&mut self.active_hint);
+        if let Some(old_active_file) = old_active_file {
             assert!(edit.need_freeze.is_some());
-            let old_active_file = old_active_file.unwrap();
+            self.write_hint_file_logged(old_active_file.get_file_id(), old_hint.into_values());
             self.freeze_files
                 .insert(old_active_file.get_file_id(), old_active_file);
         }
-        Ok(active_file.clone())
+        if !self.obsolete_files.is_empty() {
+            self.remove_obsolete_files();
+        }
+        Ok(active_file)
+    }
+
+    /// Sync the active file and the db directory, and freeze the active
+    /// file if `freeze`, see `BitcaskDB::flush_all`.
+    fn flush(&mut self, freeze: bool) -> DBResult<()> {
+        if let Some(file) = self.active_file.clone() {
+            if let Err(e) = file.sync() {
+                return Err(self.set_bg_error(e));
+            }
+            self.record_sync();
+            if freeze {
+                self.prepare_new_active_file()?;
+            }
+        }
+        self.options
+            .env
+            .sync_dir(&self.path)
+            .map_err(|e| self.set_bg_error(from_io_error(e)))
+    }
+
+    /// Flush and leave the db in its final state: the active file is
+    /// frozen with its hint, so that the next open loads the hint instead
+    /// of scanning it. The LOCK is released even if this fails.
+    fn close(&mut self) -> DBResult<()> {
+        let result = self
+            .check_bg_error()
+            .and_then(|_| self.flush(false))
+            .and_then(|_| self.freeze_active_file());
+        self.lock_file = None;
+        result
+    }
+
+    /// Freeze the active file in the manifest without a new one. An empty
+    /// one stays active for the next open.
+    fn freeze_active_file(&mut self) -> DBResult<()> {
+        if let Some(file) = self.active_file.take_if(|x| x.get_offset() > 0) {
+            let hint = std::mem::take(&mut self.active_hint);
+            self.write_hint_file_logged(file.get_file_id(), hint.into_values());
+            let mut edit = VersionEdit {
+                need_freeze: Some(file.get_file_id()),
+                ..Default::default()
+            };
+            self.log_and_apply(&mut edit)?;
+            self.freeze_files.insert(file.get_file_id(), file);
+        }
+        self.remove_obsolete_files();
+        Ok(())
+    }
+
+    /// Save `edit` to the manifest, a failure is latched since the
+    /// manifest may be left behind the files.
+    fn log_and_apply(&mut self, edit: &mut VersionEdit) -> DBResult<()> {
+        self.version_set
+            .log_and_apply(edit)
+            .map_err(|e| self.set_bg_error(e))
+    }
+
+    /// Latch `e` unless an earlier error is, and hand it back.
+    fn set_bg_error(&mut self, e: DBError) -> DBError {
+        if self.bg_error.is_none() {
+            log::warn!("writes stop on background error: {:?}", e);
+            self.bg_error = Some(Arc::new(e.duplicate()));
+        }
+        e
+    }
+
+    fn check_bg_error(&self) -> DBResult<()> {
+        match &self.bg_error {
+            Some(e) => Err(DBError::Background(e.clone())),
+            None => Ok(()),
+        }
+    }
+
+    /// Clear a latched io error once the active file is sound again: it is
+    /// cut where the failed write started and synced, then frozen so that
+    /// the next writes go to a new file and a new manifest if its last
+    /// write failed. Any other error stays.
+    fn resume(&mut self) -> DBResult<()> {
+        match &self.bg_error {
+            None => return Ok(()),
+            Some(e) if e.kind() != ErrorKind::Io => return self.check_bg_error(),
+            Some(_) => {}
+        }
+        if let Some(file) = &self.active_file {
+            if let Some(offset) = self.failed_write_offset {
+                file.truncate(offset)?;
+                self.failed_write_offset = None;
+            }
+            file.sync()?;
+        }
+        self.options
+            .env
+            .sync_dir(&self.path)
+            .map_err(from_io_error)?;
+        self.record_sync();
+        self.bg_error = None;
+        self.prepare_new_active_file()?;
+        Ok(())
+    }
+
+    /// Pick the writers the leader at the front of the queue writes along
+    /// with its own batch, returns their number and whether the group is
+    /// synced. A group which is not synced stops before a writer asking
+    /// for a sync.
+    fn group_writers(&self) -> (usize, bool) {
+        let sync = self.writers[0].sync;
+        let first_size = self.writers[0].batch().encoded_size();
+        let max_size = if first_size <= SMALL_GROUP_GROWTH {
+            first_size + SMALL_GROUP_GROWTH
+        } else {
+            MAX_GROUP_SIZE
+        };
+        let mut size = 0;
+        let mut len = 0;
+        for writer in &self.writers {
+            let writer_size = writer.batch().encoded_size();
+            if len > 0 && ((writer.sync && !sync) || size + writer_size > max_size) {
+                break;
+            }
+            size += writer_size;
+            len += 1;
+        }
+        (len, sync)
+    }
+
+    /// Whether the sync policy wants the group of `writes` writes taking
+    /// `bytes` bytes to be synced, `SyncPolicy::Always` marks the writers
+    /// themselves.
+    fn sync_due(&self, writes: usize, bytes: usize) -> bool {
+        let stats = &self.sync_stats;
+        match self.options.sync_policy {
+            SyncPolicy::EveryN(n) => stats.unsynced_writes + writes as u64 >= n,
+            SyncPolicy::BytesWritten(n) => stats.unsynced_bytes + bytes as u64 >= n,
+            _ => false,
+        }
+    }
+
+    fn record_append(&mut self, writes: usize, bytes: usize, synced: bool) {
+        let stats = &mut self.sync_stats;
+        stats.writes += writes as u64;
+        stats.bytes_written += bytes as u64;
+        stats.unsynced_writes += writes as u64;
+        stats.unsynced_bytes += bytes as u64;
+        if synced {
+            self.record_sync();
+        }
+    }
+
+    /// Called after a sync of the active file, or of the file frozen
+    /// with the last writes.
+    fn record_sync(&mut self) {
+        let stats = &mut self.sync_stats;
+        stats.syncs += 1;
+        stats.unsynced_writes = 0;
+        stats.unsynced_bytes = 0;
+    }
+
+    /// Encode the batches of the first `len` writers one after another,
+    /// each one keeps its own framing so that recovery still applies every
+    /// batch as a whole.
+    fn encode_group(&mut self, len: usize) -> DBResult<WriteGroup> {
+        let log = match self.active_file.clone() {
+            Some(x) if x.get_offset() < self.options.target_file_size => x,
+            _ => self.prepare_new_active_file()?,
+        };
+        // the sequences are handed out under the core lock, so they grow
+        // in the order of the log.
+        let ts = now_millis();
+        let mut seq = self.version_set.last_sequence() + 1;
+        let mut data = vec![];
+        let mut handles = vec![];
+        for writer in self.writers.iter().take(len) {
+            let batch = writer.batch();
+            let base = data.len() as u64;
+            let (bytes, records) =
+                batch.consume_by(seq, ts, |x, seq, offset, length| KeyAndEntryHandle {
+                    op_type: x.op_type,
+                    key: x.key.clone(),
+                    handle: EntryHandle {
+                        file_id: log.get_file_id(),
+                        offset: base + offset,
+                        length,
+                    },
+                    seq,
+                    ts,
+                    expire_at: x.expire_at,
+                });
+            data.extend_from_slice(&bytes);
+            handles.extend(records);
+            seq += batch.len() as u64;
+        }
+        self.version_set.set_last_sequence(seq - 1);
+        Ok(WriteGroup { log, data, handles })
+    }
+
+    /// Write the hint file of a freeze file from the latest records of
+    /// `file_id`, collected as they were appended. It holds the ones which
+    /// are still live according to `mem_index`, and the deletions which
+    /// must keep hiding the keys in older files. An expired put is written
+    /// as a deletion.
+    fn write_hint_file(
+        &self,
+        file_id: FileId,
+        records: impl IntoIterator<Item = HintEntry>,
+    ) -> DBResult<()> {
+        let now = now_millis();
+        let read_state = self.read_state.read().unwrap();
+        let entries: Vec<_> = records
+            .into_iter()
+            .filter_map(
+                |mut e| match read_state.liveness(e.op_type, &e.key, &e.handle, now) {
+                    Liveness::Live => Some(e),
+                    Liveness::Tombstone => {
+                        e.op_type = OpType::Del;
+                        e.expire_at = 0;
+                        Some(e)
+                    }
+                    Liveness::Dead => None,
+                },
+            )
+            .collect();
+        drop(read_state);
+        hint::write_hint_file(
+            self.options.env.as_ref(),
+            self.path.clone(),
+            file_id,
+            &entries,
+        )
+    }
+
+    /// `write_hint_file`, the hint only speeds up the next recovery so
+    /// failing to write it is not an error.
+    fn write_hint_file_logged(
+        &self,
+        file_id: FileId,
+        records: impl IntoIterator<Item = HintEntry>,
+    ) {
+        if let Err(e) = self.write_hint_file(file_id, records) {
+            log::warn!("failed to write hint for file {}: {:?}", file_id, e);
+        }
+    }
+
+    /// Load the index of a freeze file from its hint file, returns false
+    /// if the hint is missing or broken and the file must be scanned.
+    fn load_hint_file(&mut self, file: &LogFile, deleted: &mut HashMap<Vec<u8>, u64>) -> bool {
+        let env = self.options.env.as_ref();
+        let entries = match hint::read_hint_file(env, self.path.clone(), file.get_file_id()) {
+            Ok(Some(entries)) => entries,
+            Ok(None) => return false,
+            Err(e) => {
+                log::warn!("ignore hint of file {}: {:?}", file.get_file_id(), e);
+                return false;
+            }
+        };
+        let file_size = file.get_offset();
+        if entries
+            .iter()
+            .any(|e| e.handle.offset + e.handle.length > file_size)
+        {
+            log::warn!("ignore hint of file {}: out of range", file.get_file_id());
+            return false;
+        }
+        let mut read_state = self.read_state.write().unwrap();
+        for e in entries {
+            self.version_set.set_last_sequence(e.seq);
+            let entry = IndexEntry {
+                handle: e.handle,
+                seq: e.seq,
+                expire_at: e.expire_at,
+            };
+            read_state.recover(e.op_type, e.key, entry, deleted);
+        }
+        true
     }
 
+    /// Rebuild the in-memory state by replaying the files referenced by
+    /// the manifest, the record of a key with the largest sequence wins.
+    /// The recorded active file is frozen and a fresh one is prepared to
+    /// accept new writes.
     fn recovery(&mut self) -> DBResult<()> {
-        todo!()
+        let env = self.options.env.clone();
+        if !env.exists(&self.path) {
+            if !self.options.create_if_missing {
+                return Err(DBError::NotFound(self.path.display().to_string()));
+            }
+            self.options
+                .env
+                .create_dir_all(&self.path)
+                .map_err(from_io_error)?;
+        }
+        // checked before the LOCK is created, a directory which is not a
+        // db is left as it was.
+        let current_path = FileType::Current.get_full_filepath(self.path.clone(), 0);
+        if !env.exists(&current_path) && !self.options.create_if_missing {
+            return Err(DBError::NotFound(self.path.display().to_string()));
+        }
+        self.lock_db()?;
+        if env.exists(&current_path) && self.options.error_if_exists {
+            return Err(DBError::AlreadyExists(self.path.display().to_string()));
+        }
+        self.version_set.recovery(false)?;
+        let version = self.version_set.current();
+        if self.version_set.used_fallback() {
+            self.set_aside_unreferenced_files(&version.live_file_ids())?;
+        }
+
+        let mut file_types = HashMap::new();
+        for filename in env.list(&self.path).map_err(from_io_error)? {
+            if let Some((file_type @ (FileType::Log | FileType::Rewrite), file_id)) =
+                FileType::parse_filename(&filename)
+            {
+                file_types.insert(file_id, file_type);
+            }
+        }
+
+        let mut deleted = HashMap::new();
+        for file_id in version.live_file_ids() {
+            let file_type = match file_types.get(&file_id) {
+                Some(file_type) => *file_type,
+                None => {
+                    return Err(corruption(&format!(
+                        "missing file {} referenced by manifest",
+                        file_id
+                    )))
+                }
+            };
+            let path = file_type.get_full_filepath(self.path.clone(), file_id);
+            let file = Arc::new(LogFile::open(self.options.env.as_ref(), file_id, path)?);
+            let is_active = file_id == version.mut_id;
+            if is_active || !self.load_hint_file(&file, &mut deleted) {
+                self.replay_log_file(&file, is_active, &mut deleted)?;
+            }
+            self.read_state
+                .write()
+                .unwrap()
+                .files_mut()
+                .insert(file_id, file.clone());
+            if is_active {
+                self.active_file = Some(file);
+            } else {
+                self.freeze_files.insert(file_id, file);
+            }
+        }
+
+        // an active file left empty, by an open without writes, takes the
+        // writes again rather than another file and manifest edit.
+        if self.active_file.as_ref().is_none_or(|x| x.get_offset() > 0) {
+            self.prepare_new_active_file()?;
+        }
+        self.remove_obsolete_files();
+        Ok(())
+    }
+
+    /// Opened from an older manifest, the log and rewrite files which only
+    /// the lost one referenced may hold the newest writes. Rather than
+    /// leave them to the orphan cleanup, they and their hints are renamed
+    /// to `<name>.lost`, a name the db never touches again, so that they
+    /// can be salvaged by hand.
+    fn set_aside_unreferenced_files(&self, live_ids: &[FileId]) -> DBResult<()> {
+        let env = self.options.env.clone();
+        let mut renamed = false;
+        for filename in env.list(&self.path).map_err(from_io_error)? {
+            match FileType::parse_filename(&filename) {
+                Some((FileType::Log | FileType::Rewrite | FileType::Hint, file_id))
+                    if !live_ids.contains(&file_id) => {}
+                _ => continue,
+            }
+            let from = self.path.join(&filename);
+            let to = self.path.join(format!("{}.lost", filename));
+            env.rename(&from, &to).map_err(from_io_error)?;
+            log::error!(
+                "{} is not referenced by the fallback manifest, moved to {}",
+                from.display(),
+                to.display()
+            );
+            renamed = true;
+        }
+        if renamed {
+            env.sync_dir(&self.path).map_err(from_io_error)?;
+        }
+        Ok(())
+    }
+
+    /// Take the exclusive lock of the db directory, so that only one
+    /// `BitcaskDB` at a time works on it.
+    fn lock_db(&mut self) -> DBResult<()> {
+        let lock_path = FileType::Lock.get_full_filepath(self.path.clone(), 0);
+        let lock_file = match self.options.env.lock(&lock_path) {
+            Ok(lock_file) => lock_file,
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                return Err(DBError::Locked(lock_path.display().to_string()))
+            }
+            Err(e) => return Err(io_error(format!("lock {}", lock_path.display()), e)),
+        };
+        self.lock_file = Some(lock_file);
+        Ok(())
+    }
+
+    /// Replay every record of `file` into the index. A broken record at
+    /// the end of the last active log, with nothing decodable after it, is
+    /// a torn write and handled by `tail_corruption`, any other one by
+    /// `mid_file_corruption`. A write batch is broken as a whole if any
+    /// part of it is.
+    ///
+    /// Only the active log can be torn by a crash: a log is synced before
+    /// the manifest freezes it, and a rewrite file before the manifest
+    /// refers to it. A broken end of any other file is damage, which could
+    /// as well be a broken length in the middle of it, so it is not cut
+    /// off under the tail policy.
+    fn replay_log_file(
+        &mut self,
+        file: &LogFile,
+        is_newest_log: bool,
+        deleted: &mut HashMap<Vec<u8>, u64>,
+    ) -> DBResult<()> {
+        let mut read_state = self.read_state.write().unwrap();
+        let mut offset = 0;
+        loop {
+            let is_tail = match file.read_entry_at(offset, true)? {
+                LogRecord::Eof => return Ok(()),
+                LogRecord::Incomplete => is_newest_log && file.is_last_record(offset)?,
+                LogRecord::Corrupted { length } => {
+                    is_newest_log
+                        && offset + length == file.get_offset()
+                        && file.is_last_record(offset)?
+                }
+                record @ (LogRecord::Entry(..) | LogRecord::Batch { .. }) => {
+                    let (entries, length) = record.into_entries().unwrap();
+                    offset += length;
+                    for (entry, handle) in entries {
+                        self.version_set.set_last_sequence(entry.seq);
+                        if is_newest_log {
+                            let hint_entry = HintEntry {
+                                op_type: entry.op_type,
+                                key: entry.key.clone(),
+                                handle,
+                                seq: entry.seq,
+                                ts: entry.ts.unwrap_or(0),
+                                expire_at: entry.expire_at,
+                            };
+                            self.active_hint.insert(entry.key.clone(), hint_entry);
+                        }
+                        let index_entry = IndexEntry {
+                            handle,
+                            seq: entry.seq,
+                            expire_at: entry.expire_at,
+                        };
+                        read_state.recover(entry.op_type, entry.key, index_entry, deleted);
+                    }
+                    continue;
+                }
+            };
+            let policy = if is_tail {
+                self.options.tail_corruption
+            } else {
+                self.options.mid_file_corruption
+            };
+            return match policy {
+                CorruptionPolicy::Truncate => {
+                    log::warn!(
+                        "truncate log file {} from {} to {} because of a broken record",
+                        file.get_file_id(),
+                        file.get_offset(),
+                        offset
+                    );
+                    file.truncate(offset)
+                }
+                CorruptionPolicy::Fail => Err(corruption_at(
+                    "broken record in log file",
+                    file.get_file_id(),
+                    offset,
+                )),
+            };
+        }
+    }
+
+    fn start_compaction(&mut self, file_ids: &[FileId]) -> DBResult<Compaction> {
+        self.check_bg_error()?;
+        if self.compaction_running {
+            return Err(invalid_argument("another compaction is running"));
+        }
+        let mut file_ids = file_ids.to_vec();
+        file_ids.sort_unstable();
+        file_ids.dedup();
+        let mut inputs = vec![];
+        for file_id in &file_ids {
+            match self.freeze_files.get(file_id) {
+                Some(file) => inputs.push(file.clone()),
+                None => return Err(invalid_argument("compaction input is not a freeze file")),
+            }
+        }
+        let keep_tombstones_after = self
+            .freeze_files
+            .keys()
+            .filter(|x| !file_ids.contains(x))
+            .min()
+            .copied()
+            .unwrap_or(FileId::MAX);
+
+        // the outputs never exceed the inputs, reserve enough ids for them
+        // and move the writes to a newer active file.
+        let input_size: u64 = inputs.iter().map(|x| x.get_offset()).sum();
+        let target_file_size = self.options.target_file_size.max(1);
+        let num_outputs = input_size / target_file_size + 2;
+        let mut output_ids: Vec<_> = (0..num_outputs)
+            .map(|_| self.version_set.new_logfile_id())
+            .collect();
+        output_ids.reverse();
+        self.prepare_new_active_file()?;
+
+        self.compaction_running = true;
+        Ok(Compaction {
+            dbpath: self.path.clone(),
+            inputs,
+            output_ids,
+            outputs: vec![],
+            keep_tombstones_after,
+            moved: vec![],
+            output_hints: HashMap::new(),
+            dropped: vec![],
+        })
+    }
+
+    /// Keep the records the index still points to, and the tombstones
+    /// which have to keep hiding keys in older unmerged files. The expired
+    /// puts which are not kept are recorded in `dropped`.
+    fn filter_live(
+        &self,
+        compaction: &mut Compaction,
+        records: Vec<(OwnedEntry, EntryHandle)>,
+    ) -> Vec<(OwnedEntry, EntryHandle)> {
+        let now = now_millis();
+        let read_state = self.read_state.read().unwrap();
+        let mut live = vec![];
+        for (entry, handle) in records {
+            match read_state.liveness(entry.op_type, &entry.key, &handle, now) {
+                Liveness::Live => live.push((entry, handle)),
+                Liveness::Tombstone if handle.file_id > compaction.keep_tombstones_after => {
+                    live.push((entry, handle))
+                }
+                Liveness::Tombstone if entry.op_type == OpType::Put => {
+                    compaction.dropped.push((entry.key, handle))
+                }
+                Liveness::Tombstone | Liveness::Dead => {}
+            }
+        }
+        live
+    }
+
+    /// Swap the inputs for the outputs in the manifest and repoint the
+    /// index to the copied records which have not been overwritten since.
+    fn finish_compaction(&mut self, compaction: &mut Compaction) -> DBResult<()> {
+        let input_ids: Vec<_> = compaction.inputs.iter().map(|x| x.get_file_id()).collect();
+        let output_ids: Vec<_> = compaction.outputs.iter().map(|x| x.get_file_id()).collect();
+        let mut edit = VersionEdit {
+            compact_input_imm: Some(input_ids.clone()),
+            compact_output_imm: Some(output_ids),
+            ..Default::default()
+        };
+        self.log_and_apply(&mut edit)?;
+
+        // repoint the index and swap the files at once, a reader never
+        // sees a handle to a file it cannot find.
+        let mut read_state = self.read_state.write().unwrap();
+        let mem_index = &mut read_state.mem_index;
+        for (key, old_handle, new_handle) in &compaction.moved {
+            if let Some(entry) = mem_index.get_mut(key) {
+                if entry.handle == *old_handle {
+                    entry.handle = *new_handle;
+                }
+            }
+        }
+        for (key, old_handle) in &compaction.dropped {
+            if mem_index.get(key).map(|x| x.handle) == Some(*old_handle) {
+                mem_index.remove(key);
+            }
+        }
+        let files = read_state.files_mut();
+        for file_id in &input_ids {
+            files.remove(file_id);
+        }
+        for output in &compaction.outputs {
+            files.insert(output.get_file_id(), output.clone());
+        }
+        drop(read_state);
+        for file_id in &input_ids {
+            if let Some(file) = self.freeze_files.remove(file_id) {
+                self.obsolete_files.push(file);
+            }
+        }
+        for output in &compaction.outputs {
+            self.freeze_files
+                .insert(output.get_file_id(), output.clone());
+            let hint = compaction.output_hints.remove(&output.get_file_id());
+            self.write_hint_file_logged(output.get_file_id(), hint.unwrap_or_default());
+        }
+        log::info!(
+            "compacted files {:?} into {:?}",
+            input_ids,
+            edit.compact_output_imm
+        );
+        Ok(())
+    }
+
+    /// Delete the files which are not referenced by the current version.
+    /// A merged file is deleted only after the last reader holding it is
+    /// gone, it stays pending until a later call otherwise. Leftovers of
+    /// a crash are deleted as well, unless a compaction is creating files.
+    fn remove_obsolete_files(&mut self) {
+        let mut pending = vec![];
+        for file in std::mem::take(&mut self.obsolete_files) {
+            if Arc::strong_count(&file) > 1 {
+                pending.push(file);
+                continue;
+            }
+            for file_type in [FileType::Log, FileType::Rewrite, FileType::Hint] {
+                self.delete_file(file_type, file.get_file_id());
+            }
+            self.row_cache.erase_file(file.get_file_id());
+        }
+        self.obsolete_files = pending;
+
+        if self.compaction_running {
+            return;
+        }
+        let filenames = match self.options.env.list(&self.path) {
+            Ok(filenames) => filenames,
+            Err(e) => {
+                log::warn!("failed to list {}: {:?}", self.path.display(), e);
+                return;
+            }
+        };
+        let version = self.version_set.current();
+        let live_ids = version.live_file_ids();
+        let pending_ids: Vec<_> = self
+            .obsolete_files
+            .iter()
+            .map(|x| x.get_file_id())
+            .collect();
+        for filename in filenames {
+            let (file_type, file_id) = match FileType::parse_filename(&filename) {
+                Some(x) => x,
+                None => continue,
+            };
+            let keep = match file_type {
+                FileType::Log | FileType::Rewrite => {
+                    live_ids.contains(&file_id) || pending_ids.contains(&file_id)
+                }
+                FileType::Hint => live_ids.contains(&file_id),
+                FileType::Manifest => file_id == version.manifest_id,
+                FileType::Temp => false,
+                FileType::Lock | FileType::Current => true,
+            };
+            if !keep {
+                self.delete_file(file_type, file_id);
+            }
+        }
     }
 
-    fn remove_obsolete_files(&self) {
-        todo!()
+    fn delete_file(&self, file_type: FileType, file_id: FileId) {
+        let path = file_type.get_full_filepath(self.path.clone(), file_id);
+        match self.options.env.remove(&path) {
+            Ok(_) => log::info!("delete obsolete file {}", path.display()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => log::warn!("failed to delete {}: {:?}", path.display(), e),
+        }
+    }
+}
+
+/// Read the value of the live record at `handle`, through the row cache.
+pub(crate) fn read_value(
+    row_cache: &RowCache,
+    options: &ReadOptions,
+    file: &LogFile,
+    handle: EntryHandle,
+) -> DBResult<Vec<u8>> {
+    if let Some(value) = row_cache.get(&handle) {
+        return Ok(value);
     }
+    let entry = file.read_entry(handle, options.verify_checksum)?;
+    let value = match (entry.op_type, entry.value) {
+        (OpType::Put, Some(value)) => value,
+        _ => {
+            return Err(corruption_at(
+                "index points to a deletion",
+                handle.file_id,
+                handle.offset,
+            ))
+        }
+    };
+    if options.fill_cache {
+        row_cache.insert(handle, value.clone());
+    }
+    Ok(value)
 }
 
 impl BitcaskDB {
     pub fn open<P: AsRef<Path>>(path: P, options: Options) -> DBResult<BitcaskDB> {
-        let dbcore = Arc::new(Mutex::new(BitcaskCore::new(path.as_ref().to_path_buf())));
-        let mut core = dbcore.lock().unwrap();
+        if options.sync_policy == SyncPolicy::EveryInterval(Duration::ZERO) {
+            return Err(invalid_argument("sync interval must not be zero"));
+        }
+        let options = Arc::new(options);
+        let mut core = BitcaskCore::new(path.as_ref().to_path_buf(), options.clone());
         core.recovery()?;
+        let read_state = core.read_state.clone();
+        let row_cache = core.row_cache.clone();
+        let core = Arc::new(Mutex::new(core));
+        let sync_thread = match options.sync_policy {
+            SyncPolicy::EveryInterval(interval) => {
+                Some(SyncThread::spawn(Arc::downgrade(&core), interval))
+            }
+            _ => None,
+        };
         Ok(BitcaskDB {
-            options: Arc::new(options.clone()),
-            core: dbcore.clone(),
+            options,
+            core,
+            read_state,
+            row_cache,
+            writers_cv: Condvar::new(),
+            sync_thread,
+            closed: false,
         })
     }
 
@@ -100,78 +1144,2022 @@ impl BitcaskDB {
         self.write(options, &batch)
     }
 
+    /// Put a key which `get` and iterators no longer see once `ttl` has
+    /// passed, its records are dropped by a later compaction.
+    pub fn put_with_ttl(
+        &self,
+        options: WriteOptions,
+        key: &[u8],
+        value: &[u8],
+        ttl: Duration,
+    ) -> DBResult<()> {
+        let mut batch = WriteBatch::new();
+        batch.put_with_ttl(key, value, ttl);
+        self.write(options, &batch)
+    }
+
     pub fn delete(&self, options: WriteOptions, key: &[u8]) -> DBResult<()> {
         let mut batch = WriteBatch::new();
         batch.delete(key);
         self.write(options, &batch)
     }
 
+    /// Apply the batch atomically. Concurrent writes queue up, the first
+    /// one in the queue leads: it appends the batches of a group of them
+    /// at once, with at most one sync, and hands the result to every
+    /// writer of the group. The core lock is released during the io so
+    /// that more writes queue up meanwhile.
     pub fn write(&self, options: WriteOptions, batch: &WriteBatch) -> DBResult<()> {
         let mut core = self.core.lock().unwrap();
-        let mut_log = match core.active_file.clone() {
-            Some(x) if x.get_offset() < self.options.target_file_size => x.clone(),
-            _ => match core.prepare_new_active_file() {
-                Ok(f) => f.clone(),
-                Err(e) => return Err(e),
-            },
+        let id = core.next_writer_id;
+        core.next_writer_id += 1;
+        core.writers.push_back(Writer {
+            id,
+            batch: NonNull::from(batch),
+            sync: options.sync || self.options.sync_policy == SyncPolicy::Always,
+        });
+        loop {
+            if let Some(result) = core.write_results.remove(&id) {
+                return result;
+            }
+            if core.writers[0].id == id {
+                break;
+            }
+            core = self.writers_cv.wait(core).unwrap();
+        }
+
+        let (len, sync) = core.group_writers();
+        let result = match core.check_bg_error().and_then(|_| core.encode_group(len)) {
+            Ok(group) if group.data.is_empty() => Ok(()),
+            Ok(group) => {
+                let sync = sync || core.sync_due(len, group.data.len());
+                let start = group.log.get_offset();
+                // the writers behind wait for their turn and `logging`
+                // keeps the active file in place, so the append goes on
+                // without the lock.
+                core.logging = true;
+                drop(core);
+                let written = group.log.append(&group.data).and_then(|written| {
+                    if sync {
+                        group.log.sync()?;
+                    }
+                    Ok(written)
+                });
+                core = self.core.lock().unwrap();
+                core.logging = false;
+                match written {
+                    Ok(written) => {
+                        core.record_append(len, group.data.len(), sync);
+                        // still the leader, so the index is updated in the
+                        // order of the log.
+                        let core = &mut *core;
+                        let mut read_state = core.read_state.write().unwrap();
+                        for h in group.handles {
+                            let handle = EntryHandle {
+                                offset: written.offset + h.handle.offset,
+                                ..h.handle
+                            };
+                            let hint_entry = HintEntry {
+                                op_type: h.op_type,
+                                key: h.key.clone(),
+                                handle,
+                                seq: h.seq,
+                                ts: h.ts,
+                                expire_at: h.expire_at,
+                            };
+                            core.active_hint.insert(h.key.clone(), hint_entry);
+                            let entry = IndexEntry {
+                                handle,
+                                seq: h.seq,
+                                expire_at: h.expire_at,
+                            };
+                            read_state.apply(h.op_type, h.key, entry);
+                        }
+                        Ok(())
+                    }
+                    // none of the group is in the index, `resume` cuts it
+                    // off the file too.
+                    Err(e) => {
+                        core.failed_write_offset.get_or_insert(start);
+                        Err(core.set_bg_error(e))
+                    }
+                }
+            }
+            Err(e) => Err(e),
         };
-        let handles = batch.consume_by(|x| {
-            mut_log.write_entry(x).map(|h| KeyAndEntryHandle {
-                key: x.key.clone(),
-                handle: h,
-            })
-        })?;
 
-        if options.sync {
-            mut_log.sync()?;
-            // TODO record bg error ?
+        let core = &mut *core;
+        for writer in core.writers.drain(..len).skip(1) {
+            let result = result.as_ref().map(|_| ()).map_err(DBError::duplicate);
+            core.write_results.insert(writer.id, result);
         }
+        self.writers_cv.notify_all();
+        result
+    }
+
+    /// The counters of the writes to the log and of their syncs.
+    pub fn sync_stats(&self) -> SyncStats {
+        self.core.lock().unwrap().sync_stats
+    }
+
+    /// Lock the core once no leader is appending to the active file, so
+    /// that it can be swapped.
+    fn lock_idle_core(&self) -> MutexGuard<'_, BitcaskCore> {
+        let core = self.core.lock().unwrap();
+        self.writers_cv.wait_while(core, |x| x.logging).unwrap()
+    }
 
-        for h in handles {
-            core.mem_index.insert(h.key, h.handle);
+    /// Look up the live record of `key` in the state `options` reads.
+    fn find(&self, options: &ReadOptions, key: &[u8]) -> Option<(EntryHandle, Arc<LogFile>)> {
+        // only look up under the lock, the file is read without it.
+        let now = now_millis();
+        let lookup = |read_state: &ReadState| {
+            read_state
+                .lookup(key, now)
+                .map(|entry| read_state.locate(&entry.handle))
+        };
+        match &options.snapshot {
+            Some(snapshot) => lookup(snapshot.state()),
+            None => lookup(&self.read_state.read().unwrap()),
         }
-        Ok(())
     }
 
     pub fn get(&self, options: ReadOptions, key: &[u8]) -> DBResult<Option<Vec<u8>>> {
-        let core = self.core.lock().unwrap();
-        let handle = match core.mem_index.get(key) {
+        match self.find(&options, key) {
+            Some((handle, file)) => read_value(&self.row_cache, &options, &file, handle).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Like `get`, also returns when the value was written and the
+    /// sequence number of the write. The record is always read from the
+    /// log, the row cache only holds values.
+    pub fn get_with_meta(
+        &self,
+        options: ReadOptions,
+        key: &[u8],
+    ) -> DBResult<Option<(Vec<u8>, ValueMeta)>> {
+        let (handle, file) = match self.find(&options, key) {
+            Some(found) => found,
             None => return Ok(None),
-            Some(handle) => handle,
         };
+        let entry = file.read_entry(handle, options.verify_checksum)?;
+        let meta = ValueMeta {
+            timestamp: entry.ts.unwrap_or(0),
+            sequence: entry.seq,
+        };
+        match (entry.op_type, entry.value) {
+            (OpType::Put, Some(value)) => Ok(Some((value, meta))),
+            _ => Err(corruption_at(
+                "index points to a deletion",
+                handle.file_id,
+                handle.offset,
+            )),
+        }
+    }
 
-        assert!(handle.file_id != INVALID_FILE_ID);
-        let search_target_fn = || match core
-            .active_file
-            .clone()
-            .filter(|x| x.get_file_id() == handle.file_id)
-        {
-            Some(x) => return Some(x),
-            None => {
-                return match core.freeze_files.get(&handle.file_id) {
-                    Some(x) => Some(x.clone()),
-                    None => None,
-                }
-            }
+    /// Take a snapshot of the current state, see `ReadOptions::snapshot`.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot::new(self.read_state.read().unwrap().clone())
+    }
+
+    fn new_iterator(
+        &self,
+        options: ReadOptions,
+        bounds: (Bound<Vec<u8>>, Bound<Vec<u8>>),
+    ) -> DBIterator {
+        // an iterator over a snapshot looks up a private copy of it
+        let read_state = match &options.snapshot {
+            Some(snapshot) => Arc::new(RwLock::new(snapshot.state().clone())),
+            None => self.read_state.clone(),
+        };
+        DBIterator::new(read_state, self.row_cache.clone(), options, bounds)
+    }
+
+    /// An iterator over all the keys in order, it is not positioned until
+    /// one of the seek methods is called.
+    pub fn iter(&self, options: ReadOptions) -> DBIterator {
+        self.new_iterator(options, (Bound::Unbounded, Bound::Unbounded))
+    }
+
+    /// An iterator over the keys in `range`, positioned at the first one.
+    pub fn range<K: AsRef<[u8]>, R: RangeBounds<K>>(
+        &self,
+        options: ReadOptions,
+        range: R,
+    ) -> DBIterator {
+        let to_owned = |bound: Bound<&K>| match bound {
+            Bound::Included(x) => Bound::Included(x.as_ref().to_vec()),
+            Bound::Excluded(x) => Bound::Excluded(x.as_ref().to_vec()),
+            Bound::Unbounded => Bound::Unbounded,
         };
-        let file = search_target_fn().expect("the index of key points to a non-exist place");
-        let entry = file.read_entry(handle.clone(), options.verify_checksum)?;
-        match entry.op_type {
-            OpType::Put => return Ok(entry.value),
-            OpType::Del => return Ok(None),
+        let bounds = (to_owned(range.start_bound()), to_owned(range.end_bound()));
+        let mut iter = self.new_iterator(options, bounds);
+        iter.seek_to_first();
+        iter
+    }
+
+    /// An iterator over the keys starting with `prefix`, positioned at
+    /// the first one.
+    pub fn prefix(&self, options: ReadOptions, prefix: &[u8]) -> DBIterator {
+        // the least key greater than every key with the prefix, none if
+        // the prefix is all 0xff.
+        let mut end = prefix.to_vec();
+        while end.last() == Some(&u8::MAX) {
+            end.pop();
         }
+        let end = match end.last_mut() {
+            Some(x) => {
+                *x += 1;
+                Bound::Excluded(end)
+            }
+            None => Bound::Unbounded,
+        };
+        let mut iter = self.new_iterator(options, (Bound::Included(prefix.to_vec()), end));
+        iter.seek_to_first();
+        iter
     }
 
+    /// The hit and miss counters of the row cache, all zero when
+    /// `Options::row_cache_size` is 0.
+    pub fn row_cache_stats(&self) -> CacheStats {
+        self.row_cache.stats()
+    }
+
+    /// Make every write so far durable by syncing the active file and the
+    /// db directory. With `Options::hint_on_flush` the active file is
+    /// frozen too and the next writes go to a new file.
     pub fn flush_all(&self) -> DBResult<()> {
-        todo!()
+        let mut core = self.lock_idle_core();
+        core.check_bg_error()?;
+        core.flush(self.options.hint_on_flush)
     }
-}
 
-// impl EntryConsumer for BTreeMap<Vec<u8>, EntryHandle> {
-//     fn consume(&mut self, entry: OwnedEntry) {
-//         match entry.op_type {
-//             OpType::Put => self.insert(entry.key, entry.value.unwrap()),
-//             OpType::Del => self.remove(entry.key.as_ref()),
-//         };
-//     }
-// }
+    /// Clear the error which stopped the writes if it is an io error,
+    /// which may be transient. The writes after the last acknowledged one
+    /// are cut off the active file, and the next writes go to a new file.
+    /// Fails with the error if it can not be cleared.
+    pub fn resume(&self) -> DBResult<()> {
+        self.lock_idle_core().resume()
+    }
+
+    /// Stop the background thread, flush, freeze the active file with its
+    /// hint and release the LOCK. Dropping the db only stops the thread
+    /// and syncs, ignoring any failure.
+    ///
+    /// With a latched background error, nothing is flushed: the LOCK is
+    /// released and the error returned. Call `resume` first to close the
+    /// db cleanly after an io error.
+    pub fn close(mut self) -> DBResult<()> {
+        self.closed = true;
+        self.sync_thread.take();
+        let result = self.lock_idle_core().close();
+        result
+    }
+
+    /// The ids of the freeze files in ascending order, the files
+    /// `compact_files` can merge.
+    pub fn freeze_file_ids(&self) -> Vec<FileId> {
+        let core = self.core.lock().unwrap();
+        let mut file_ids: Vec<_> = core.freeze_files.keys().copied().collect();
+        file_ids.sort_unstable();
+        file_ids
+    }
+
+    /// Merge all the freeze files, see `compact_files`.
+    pub fn compact(&self) -> DBResult<()> {
+        let file_ids: Vec<FileId> = {
+            let core = self.core.lock().unwrap();
+            core.check_bg_error()?;
+            core.freeze_files.keys().copied().collect()
+        };
+        if file_ids.is_empty() {
+            return Ok(());
+        }
+        self.compact_files(&file_ids)
+    }
+
+    /// Copy the records of `file_ids` which are still referenced by the
+    /// index into new rewrite files, then swap the files in one manifest
+    /// edit. The core lock is only held for short steps so reads and
+    /// writes go on during the merge.
+    ///
+    /// Every id must be one of `freeze_file_ids`, the active file or an
+    /// unknown id is an `InvalidArgument` error.
+    pub fn compact_files(&self, file_ids: &[FileId]) -> DBResult<()> {
+        let mut compaction = self.lock_idle_core().start_compaction(file_ids)?;
+        let result = self
+            .run_compaction(&mut compaction)
+            .and_then(|_| self.core.lock().unwrap().finish_compaction(&mut compaction));
+        // release the inputs before deleting them
+        drop(compaction);
+        let mut core = self.core.lock().unwrap();
+        core.compaction_running = false;
+        core.remove_obsolete_files();
+        result
+    }
+
+    fn run_compaction(&self, compaction: &mut Compaction) -> DBResult<()> {
+        for input in compaction.inputs.clone() {
+            let mut offset = 0;
+            let mut batch = vec![];
+            loop {
+                let eof = match input.read_entry_at(offset, true)? {
+                    LogRecord::Eof => true,
+                    record @ (LogRecord::Entry(..) | LogRecord::Batch { .. }) => {
+                        let (entries, length) = record.into_entries().unwrap();
+                        offset += length;
+                        batch.extend(entries);
+                        false
+                    }
+                    _ => {
+                        return Err(corruption_at(
+                            "broken record in compaction input",
+                            input.get_file_id(),
+                            offset,
+                        ))
+                    }
+                };
+                if batch.len() >= COMPACTION_BATCH_SIZE || (eof && !batch.is_empty()) {
+                    let live = self.core.lock().unwrap().filter_live(compaction, batch);
+                    batch = vec![];
+                    for (entry, handle) in live {
+                        self.copy_to_output(compaction, entry, handle)?;
+                    }
+                }
+                if eof {
+                    break;
+                }
+            }
+        }
+        for output in &compaction.outputs {
+            output.sync()?;
+        }
+        self.options
+            .env
+            .sync_dir(&compaction.dbpath)
+            .map_err(from_io_error)
+    }
+
+    /// Copy a record as it is, with its sequence, so that recovery still
+    /// settles it against the records of newer logs.
+    fn copy_to_output(
+        &self,
+        compaction: &mut Compaction,
+        entry: OwnedEntry,
+        handle: EntryHandle,
+    ) -> DBResult<()> {
+        let output = match compaction.outputs.last() {
+            Some(x) if x.get_offset() < self.options.target_file_size => x.clone(),
+            _ => {
+                let file_id = compaction.output_ids.pop();
+                debug_assert!(file_id.is_some(), "compaction output larger than input");
+                let file_id =
+                    file_id.ok_or_else(|| internal("compaction output larger than input"))?;
+                let path = FileType::Rewrite.get_full_filepath(compaction.dbpath.clone(), file_id);
+                let env = self.options.env.as_ref();
+                let output = Arc::new(LogFile::create(env, file_id, path)?);
+                compaction.outputs.push(output.clone());
+                output
+            }
+        };
+        let new_handle = output.write_entry(&entry)?;
+        compaction
+            .output_hints
+            .entry(output.get_file_id())
+            .or_default()
+            .push(HintEntry {
+                op_type: entry.op_type,
+                key: entry.key.clone(),
+                handle: new_handle,
+                seq: entry.seq,
+                ts: entry.ts.unwrap_or(0),
+                expire_at: entry.expire_at,
+            });
+        compaction.moved.push((entry.key, handle, new_handle));
+        Ok(())
+    }
+}
+
+impl Drop for BitcaskDB {
+    fn drop(&mut self) {
+        if self.closed {
+            return;
+        }
+        self.sync_thread.take();
+        // no write is in flight, they borrow the db.
+        if let Ok(mut core) = self.core.lock() {
+            if let Err(e) = core.flush(false) {
+                log::warn!("failed to flush on drop: {:?}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::path::{Path, PathBuf};
+    use std::ptr::NonNull;
+    use std::sync::Arc;
+
+    use crate::filename::FileType;
+
+    use super::{Writer, MAX_GROUP_SIZE};
+    use crate::env::{read_file, write_all_at};
+    use crate::errors::{corruption, from_io_error};
+    use crate::fault_env::{FaultEnv, FaultOp};
+    use crate::model::{OpType, ENTRY_HEADER_SIZE};
+    use crate::{
+        BitcaskDB, CorruptionPolicy, DBError, EnvFile, ErrorKind, MemEnv, OpenMode, Options,
+        ReadOptions, SyncPolicy, WriteBatch, WriteOptions,
+    };
+
+    /// A db path on a fresh in-memory env, and the options to open it with.
+    fn prepare_db() -> (PathBuf, Options) {
+        let opts = Options {
+            env: Arc::new(MemEnv::new()),
+            ..Default::default()
+        };
+        (PathBuf::from("/db"), opts)
+    }
+
+    fn open_file(opts: &Options, path: &Path) -> Box<dyn EnvFile> {
+        opts.env.open(path, OpenMode::Existing).unwrap()
+    }
+
+    fn file_len(opts: &Options, path: &Path) -> u64 {
+        open_file(opts, path).size().unwrap()
+    }
+
+    fn write_file(opts: &Options, path: &Path, data: &[u8]) {
+        let file = opts.env.open(path, OpenMode::Truncate).unwrap();
+        write_all_at(&*file, data, 0).unwrap();
+    }
+
+    #[test]
+    fn test_reopen_recovery() {
+        let (path, base) = prepare_db();
+        {
+            let db = BitcaskDB::open(&path, base.clone()).unwrap();
+            db.put(WriteOptions::default(), b"k1", b"v1").unwrap();
+            db.put(WriteOptions::default(), b"k2", b"v2").unwrap();
+            db.put(WriteOptions::default(), b"k1", b"v1-new").unwrap();
+            db.delete(WriteOptions::default(), b"k2").unwrap();
+            let mut batch = WriteBatch::new();
+            batch.put(b"k3", b"v3");
+            batch.delete(b"k4");
+            db.write(WriteOptions { sync: true }, &batch).unwrap();
+        }
+
+        for _ in 0..2 {
+            let db = BitcaskDB::open(&path, base.clone()).unwrap();
+            let read = |key: &[u8]| db.get(ReadOptions::default(), key).unwrap();
+            assert_eq!(read(b"k1"), Some(b"v1-new".to_vec()));
+            assert_eq!(read(b"k2"), None);
+            assert_eq!(read(b"k3"), Some(b"v3".to_vec()));
+            assert_eq!(read(b"k4"), None);
+        }
+
+        // writes after a reopen go to a new active file
+        {
+            let db = BitcaskDB::open(&path, base.clone()).unwrap();
+            db.put(WriteOptions::default(), b"k2", b"v2-again").unwrap();
+        }
+        let db = BitcaskDB::open(&path, base.clone()).unwrap();
+        assert_eq!(
+            db.get(ReadOptions::default(), b"k2").unwrap(),
+            Some(b"v2-again".to_vec())
+        );
+        assert_eq!(
+            db.get(ReadOptions::default(), b"k1").unwrap(),
+            Some(b"v1-new".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_recovery_across_rotated_files() {
+        let (path, base) = prepare_db();
+        let opts = Options {
+            target_file_size: 64,
+            ..base.clone()
+        };
+        {
+            let db = BitcaskDB::open(&path, opts.clone()).unwrap();
+            for i in 0..100 {
+                let key = format!("key{}", i % 10);
+                let value = format!("value{}", i);
+                db.put(WriteOptions::default(), key.as_bytes(), value.as_bytes())
+                    .unwrap();
+            }
+            db.delete(WriteOptions::default(), b"key3").unwrap();
+        }
+
+        let db = BitcaskDB::open(&path, opts).unwrap();
+        for i in 0..10 {
+            let key = format!("key{}", i);
+            let expect = match i {
+                3 => None,
+                _ => Some(format!("value{}", 90 + i).into_bytes()),
+            };
+            assert_eq!(
+                db.get(ReadOptions::default(), key.as_bytes()).unwrap(),
+                expect
+            );
+        }
+    }
+
+    /// Returns the path of the log file the kvs are written to.
+    fn write_kvs(path: &PathBuf, opts: &Options, n: usize) -> PathBuf {
+        let db = BitcaskDB::open(path, opts.clone()).unwrap();
+        for i in 0..n {
+            let key = format!("key{}", i);
+            db.put(WriteOptions::default(), key.as_bytes(), b"value")
+                .unwrap();
+        }
+        let core = db.core.lock().unwrap();
+        let file_id = core.active_file.as_ref().unwrap().get_file_id();
+        FileType::Log.get_full_filepath(path.clone(), file_id)
+    }
+
+    fn count_kvs(db: &BitcaskDB, n: usize) -> usize {
+        (0..n)
+            .filter(|i| {
+                let key = format!("key{}", i);
+                db.get(ReadOptions::default(), key.as_bytes())
+                    .unwrap()
+                    .is_some()
+            })
+            .count()
+    }
+
+    #[test]
+    fn test_recovery_torn_tail() {
+        let (path, base) = prepare_db();
+        let logfile = write_kvs(&path, &base, 10);
+        let good_len = file_len(&base, &logfile);
+        // half of a header
+        write_all_at(&*open_file(&base, &logfile), &[0; 7], good_len).unwrap();
+
+        let opts = Options {
+            tail_corruption: CorruptionPolicy::Fail,
+            ..base.clone()
+        };
+        assert!(BitcaskDB::open(&path, opts).is_err());
+
+        let db = BitcaskDB::open(&path, base.clone()).unwrap();
+        assert_eq!(count_kvs(&db, 10), 10);
+        assert_eq!(file_len(&base, &logfile), good_len);
+        db.put(WriteOptions::default(), b"key10", b"value").unwrap();
+        drop(db);
+
+        let db = BitcaskDB::open(&path, base.clone()).unwrap();
+        assert_eq!(count_kvs(&db, 11), 11);
+    }
+
+    #[test]
+    fn test_recovery_partial_batch() {
+        let (path, base) = prepare_db();
+        write_kvs(&path, &base, 10);
+        let write_batch = || {
+            let db = BitcaskDB::open(&path, base.clone()).unwrap();
+            let mut batch = WriteBatch::new();
+            batch.put(b"key0", b"new");
+            batch.put(b"key10", b"value");
+            batch.delete(b"key1");
+            db.write(WriteOptions::default(), &batch).unwrap();
+            let core = db.core.lock().unwrap();
+            let file_id = core.active_file.as_ref().unwrap().get_file_id();
+            FileType::Log.get_full_filepath(path.clone(), file_id)
+        };
+        let check_untouched = |db: &BitcaskDB| {
+            assert_eq!(count_kvs(db, 11), 10);
+            assert_eq!(
+                db.get(ReadOptions::default(), b"key0").unwrap(),
+                Some(b"value".to_vec())
+            );
+        };
+
+        // cut the last record of the batch, the whole batch is dropped
+        let batch_log = write_batch();
+        let len = file_len(&base, &batch_log);
+        open_file(&base, &batch_log).set_len(len - 3).unwrap();
+        let db = BitcaskDB::open(&path, base.clone()).unwrap();
+        check_untouched(&db);
+        assert_eq!(file_len(&base, &batch_log), 0);
+        drop(db);
+
+        // a flipped byte in the middle of a complete batch
+        let batch_log = write_batch();
+        open_file(&base, &batch_log).write_at(b"X", 100).unwrap();
+        let db = BitcaskDB::open(&path, base.clone()).unwrap();
+        check_untouched(&db);
+        drop(db);
+
+        // a complete batch is applied as a whole
+        write_batch();
+        let db = BitcaskDB::open(&path, base.clone()).unwrap();
+        assert_eq!(count_kvs(&db, 11), 10);
+        assert_eq!(db.get(ReadOptions::default(), b"key1").unwrap(), None);
+        assert_eq!(
+            db.get(ReadOptions::default(), b"key0").unwrap(),
+            Some(b"new".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_recovery_corrupted_last_record() {
+        let (path, base) = prepare_db();
+        let logfile = write_kvs(&path, &base, 10);
+        let len = file_len(&base, &logfile);
+        let last_len = ENTRY_HEADER_SIZE as u64 + b"key9".len() as u64 + 1 + b"value".len() as u64;
+        // break the op type of the last record
+        open_file(&base, &logfile)
+            .write_at(&[9], len - last_len + ENTRY_HEADER_SIZE as u64 + 4)
+            .unwrap();
+
+        let db = BitcaskDB::open(&path, base.clone()).unwrap();
+        assert_eq!(count_kvs(&db, 10), 9);
+        assert_eq!(file_len(&base, &logfile), len - last_len);
+    }
+
+    #[test]
+    fn test_recovery_torn_tail_of_frozen_log() {
+        let (path, base) = prepare_db();
+        let logfile = write_kvs(&path, &base, 10);
+        let good_len = file_len(&base, &logfile);
+        // frozen by the open, with a newer log after it
+        let db = BitcaskDB::open(&path, base.clone()).unwrap();
+        db.put(WriteOptions::default(), b"key10", b"value").unwrap();
+        drop(db);
+        remove_hint_files(&path, &base);
+        write_all_at(&*open_file(&base, &logfile), &[0; 7], good_len).unwrap();
+
+        // not a crash of a write, the mid file policy decides
+        assert!(BitcaskDB::open(&path, base.clone()).is_err());
+        let opts = Options {
+            mid_file_corruption: CorruptionPolicy::Truncate,
+            ..base.clone()
+        };
+        let db = BitcaskDB::open(&path, opts).unwrap();
+        assert_eq!(count_kvs(&db, 11), 11);
+        assert_eq!(file_len(&base, &logfile), good_len);
+    }
+
+    #[test]
+    fn test_fallback_manifest_keeps_data_files() {
+        let (path, base) = prepare_db();
+        // every open rolls the manifest
+        let base = Options {
+            max_manifest_file_size: 1,
+            ..base
+        };
+        let manifest_of = |opts: &Options| {
+            let current = read_file(&*opts.env, &path.join("CURRENT")).unwrap();
+            path.join(String::from_utf8(current).unwrap())
+        };
+        let data_files = |opts: &Options| {
+            let mut names: Vec<_> = opts
+                .env
+                .list(&path)
+                .unwrap()
+                .into_iter()
+                .filter(|x| x.ends_with(".dat") || x.ends_with(".rew"))
+                .collect();
+            names.sort();
+            names
+        };
+        write_kvs(&path, &base, 2);
+        let old_manifest = manifest_of(&base);
+        let old_data = read_file(&*base.env, &old_manifest).unwrap();
+        for i in 2..4 {
+            let db = BitcaskDB::open(&path, base.clone()).unwrap();
+            db.put(
+                WriteOptions::default(),
+                format!("key{}", i).as_bytes(),
+                b"value",
+            )
+            .unwrap();
+        }
+        // the newest manifest is lost, an older one is left behind
+        base.env.remove(&manifest_of(&base)).unwrap();
+        write_file(&base, &old_manifest, &old_data);
+        let before = data_files(&base);
+
+        let opts = Options {
+            fallback_to_newest_manifest: true,
+            ..base.clone()
+        };
+        let db = BitcaskDB::open(&path, opts).unwrap();
+        assert_eq!(count_kvs(&db, 4), 2);
+        drop(db);
+        let db = BitcaskDB::open(&path, base.clone()).unwrap();
+        drop(db);
+        let mut lost = 0;
+        for name in before {
+            if base.env.exists(&path.join(format!("{}.lost", name))) {
+                lost += 1;
+            } else {
+                assert!(base.env.exists(&path.join(&name)), "{} was deleted", name);
+            }
+        }
+        assert!(lost > 0);
+    }
+
+    #[test]
+    fn test_recovery_broken_length_in_active_log() {
+        let (path, base) = prepare_db();
+        let logfile = write_kvs(&path, &base, 10);
+        let record_len =
+            ENTRY_HEADER_SIZE as u64 + b"key0".len() as u64 + 1 + b"value".len() as u64;
+        // the key size of the 3rd record runs it past the end of file
+        open_file(&base, &logfile)
+            .write_at(&[1], record_len * 2 + 28)
+            .unwrap();
+
+        // the records after it show it is not a torn write
+        assert!(matches!(
+            BitcaskDB::open(&path, base.clone()),
+            Err(DBError::Corruption { .. })
+        ));
+        let opts = Options {
+            mid_file_corruption: CorruptionPolicy::Truncate,
+            ..base.clone()
+        };
+        let db = BitcaskDB::open(&path, opts).unwrap();
+        assert_eq!(count_kvs(&db, 10), 2);
+        assert_eq!(file_len(&base, &logfile), record_len * 2);
+    }
+
+    #[test]
+    fn test_recovery_mid_file_corruption() {
+        let (path, base) = prepare_db();
+        let logfile = write_kvs(&path, &base, 10);
+        let record_len =
+            ENTRY_HEADER_SIZE as u64 + b"key0".len() as u64 + 1 + b"value".len() as u64;
+        // break the op type of the 4th record
+        open_file(&base, &logfile)
+            .write_at(&[9], record_len * 3 + ENTRY_HEADER_SIZE as u64 + 4)
+            .unwrap();
+
+        assert!(BitcaskDB::open(&path, base.clone()).is_err());
+
+        let opts = Options {
+            mid_file_corruption: CorruptionPolicy::Truncate,
+            ..base.clone()
+        };
+        let db = BitcaskDB::open(&path, opts).unwrap();
+        assert_eq!(count_kvs(&db, 10), 3);
+        assert_eq!(file_len(&base, &logfile), record_len * 3);
+    }
+
+    #[test]
+    fn test_recovery_from_hint_files() {
+        let (path, base) = prepare_db();
+        let opts = Options {
+            target_file_size: 128,
+            ..base.clone()
+        };
+        let freeze_ids = {
+            let db = BitcaskDB::open(&path, opts.clone()).unwrap();
+            for i in 0..50 {
+                let key = format!("key{}", i % 10);
+                let value = format!("value{}", i);
+                db.put(WriteOptions::default(), key.as_bytes(), value.as_bytes())
+                    .unwrap();
+            }
+            db.delete(WriteOptions::default(), b"key3").unwrap();
+            db.put(WriteOptions::default(), b"key10", b"value").unwrap();
+            let core = db.core.lock().unwrap();
+            let mut ids: Vec<_> = core.freeze_files.keys().copied().collect();
+            ids.sort_unstable();
+            ids
+        };
+        assert!(freeze_ids.len() > 2);
+        for id in &freeze_ids {
+            let hint = FileType::Hint.get_full_filepath(path.clone(), *id);
+            assert!(opts.env.exists(&hint));
+        }
+
+        let check = |db: &BitcaskDB| {
+            for i in 0..11 {
+                let key = format!("key{}", i);
+                let expect = match i {
+                    3 => None,
+                    10 => Some(b"value".to_vec()),
+                    _ => Some(format!("value{}", 40 + i).into_bytes()),
+                };
+                assert_eq!(
+                    db.get(ReadOptions::default(), key.as_bytes()).unwrap(),
+                    expect
+                );
+            }
+        };
+        check(&BitcaskDB::open(&path, opts.clone()).unwrap());
+
+        // values are not read when the hint is used, a broken value in the
+        // middle of a freeze file goes unnoticed.
+        let first = FileType::Log.get_full_filepath(path.clone(), freeze_ids[0]);
+        let original = read_file(&*opts.env, &first).unwrap();
+        let mut broken = original.clone();
+        broken[30] ^= 0xff;
+        write_file(&opts, &first, &broken);
+        BitcaskDB::open(&path, opts.clone()).unwrap();
+
+        // a broken hint falls back to a full scan
+        let hint = FileType::Hint.get_full_filepath(path.clone(), freeze_ids[0]);
+        let mut data = read_file(&*opts.env, &hint).unwrap();
+        data[0] ^= 0xff;
+        write_file(&opts, &hint, &data);
+        assert!(BitcaskDB::open(&path, opts.clone()).is_err());
+        write_file(&opts, &first, &original);
+        check(&BitcaskDB::open(&path, opts.clone()).unwrap());
+
+        // so does a missing one
+        for id in &freeze_ids {
+            let _ = opts
+                .env
+                .remove(&FileType::Hint.get_full_filepath(path.clone(), *id));
+        }
+        check(&BitcaskDB::open(&path, opts).unwrap());
+    }
+
+    fn remove_hint_files(path: &Path, opts: &Options) {
+        for name in opts.env.list(path).unwrap() {
+            if let Some((FileType::Hint, _)) = FileType::parse_filename(&name) {
+                opts.env.remove(&path.join(name)).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn test_compaction() {
+        let (path, base) = prepare_db();
+        let opts = Options {
+            target_file_size: 256,
+            ..base.clone()
+        };
+        let db = BitcaskDB::open(&path, opts.clone()).unwrap();
+        for i in 0..200 {
+            let key = format!("key{}", i % 10);
+            let value = format!("value{}", i);
+            db.put(WriteOptions::default(), key.as_bytes(), value.as_bytes())
+                .unwrap();
+        }
+        db.delete(WriteOptions::default(), b"key3").unwrap();
+        let check = |db: &BitcaskDB| {
+            for i in 0..10 {
+                let key = format!("key{}", i);
+                let expect = match i {
+                    3 => None,
+                    _ => Some(format!("value{}", 190 + i).into_bytes()),
+                };
+                assert_eq!(
+                    db.get(ReadOptions::default(), key.as_bytes()).unwrap(),
+                    expect
+                );
+            }
+        };
+
+        let inputs = db.freeze_file_ids();
+        let input_size: u64 = {
+            let core = db.core.lock().unwrap();
+            inputs
+                .iter()
+                .map(|x| core.freeze_files[x].get_offset())
+                .sum()
+        };
+        db.compact().unwrap();
+        check(&db);
+        let outputs = db.freeze_file_ids();
+        let output_size: u64 = {
+            let core = db.core.lock().unwrap();
+            let version = core.version_set.current();
+            assert!(outputs.iter().all(|x| version.imm_ids.contains(x)));
+            assert!(inputs.iter().all(|x| !version.imm_ids.contains(x)));
+            outputs
+                .iter()
+                .map(|x| core.freeze_files[x].get_offset())
+                .sum()
+        };
+        assert!(output_size * 4 < input_size);
+        for id in &outputs {
+            let rewrite = FileType::Rewrite.get_full_filepath(path.clone(), *id);
+            let log = FileType::Log.get_full_filepath(path.clone(), *id);
+            assert!(opts.env.exists(&rewrite) || opts.env.exists(&log));
+        }
+        drop(db);
+
+        check(&BitcaskDB::open(&path, opts.clone()).unwrap());
+        remove_hint_files(&path, &opts);
+        check(&BitcaskDB::open(&path, opts).unwrap());
+    }
+
+    #[test]
+    fn test_compaction_keeps_tombstones() {
+        let (path, base) = prepare_db();
+        let opts = Options {
+            target_file_size: 1,
+            ..base.clone()
+        };
+        let db = BitcaskDB::open(&path, opts.clone()).unwrap();
+        db.put(WriteOptions::default(), b"k1", b"v1").unwrap();
+        db.put(WriteOptions::default(), b"k2", b"v2").unwrap();
+        db.delete(WriteOptions::default(), b"k1").unwrap();
+        db.put(WriteOptions::default(), b"k3", b"v3").unwrap();
+        let ids = db.freeze_file_ids();
+        assert_eq!(ids.len(), 3);
+
+        // merge the file holding the tombstone only
+        db.compact_files(&ids[2..]).unwrap();
+        drop(db);
+        remove_hint_files(&path, &opts);
+        let db = BitcaskDB::open(&path, opts.clone()).unwrap();
+        assert_eq!(db.get(ReadOptions::default(), b"k1").unwrap(), None);
+        assert_eq!(
+            db.get(ReadOptions::default(), b"k2").unwrap(),
+            Some(b"v2".to_vec())
+        );
+
+        db.compact().unwrap();
+        drop(db);
+        remove_hint_files(&path, &opts);
+        let db = BitcaskDB::open(&path, opts).unwrap();
+        assert_eq!(db.get(ReadOptions::default(), b"k1").unwrap(), None);
+        assert_eq!(
+            db.get(ReadOptions::default(), b"k2").unwrap(),
+            Some(b"v2".to_vec())
+        );
+        assert_eq!(
+            db.get(ReadOptions::default(), b"k3").unwrap(),
+            Some(b"v3".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_compaction_with_concurrent_writes() {
+        let (path, base) = prepare_db();
+        let opts = Options {
+            target_file_size: 64,
+            ..base.clone()
+        };
+        let db = BitcaskDB::open(&path, opts.clone()).unwrap();
+        for i in 0..40 {
+            let key = format!("key{}", i % 4);
+            db.put(WriteOptions::default(), key.as_bytes(), b"old")
+                .unwrap();
+        }
+        let inputs = db.freeze_file_ids();
+        let mut compaction = db.core.lock().unwrap().start_compaction(&inputs).unwrap();
+        db.run_compaction(&mut compaction).unwrap();
+        // overwritten after being copied
+        db.put(WriteOptions::default(), b"key0", b"new").unwrap();
+        db.delete(WriteOptions::default(), b"key1").unwrap();
+        db.core
+            .lock()
+            .unwrap()
+            .finish_compaction(&mut compaction)
+            .unwrap();
+        db.core.lock().unwrap().compaction_running = false;
+
+        let check = |db: &BitcaskDB| {
+            let read = |key: &[u8]| db.get(ReadOptions::default(), key).unwrap();
+            assert_eq!(read(b"key0"), Some(b"new".to_vec()));
+            assert_eq!(read(b"key1"), None);
+            assert_eq!(read(b"key2"), Some(b"old".to_vec()));
+        };
+        check(&db);
+        drop(db);
+        remove_hint_files(&path, &opts);
+        check(&BitcaskDB::open(&path, opts).unwrap());
+    }
+
+    #[test]
+    fn test_remove_obsolete_files() {
+        let (path, base) = prepare_db();
+        let opts = Options {
+            target_file_size: 64,
+            ..base.clone()
+        };
+        let db = BitcaskDB::open(&path, opts.clone()).unwrap();
+        for i in 0..40 {
+            let key = format!("key{}", i % 4);
+            db.put(WriteOptions::default(), key.as_bytes(), b"value")
+                .unwrap();
+        }
+        let inputs = db.freeze_file_ids();
+        let exists = |id: u64| {
+            let log = FileType::Log.get_full_filepath(path.clone(), id);
+            opts.env.exists(&log)
+        };
+
+        // a reader still holds the first input
+        let reader = db.core.lock().unwrap().freeze_files[&inputs[0]].clone();
+        db.compact_files(&inputs).unwrap();
+        assert!(exists(inputs[0]));
+        assert!(inputs[1..].iter().all(|x| !exists(*x)));
+        assert!(inputs[1..].iter().all(|x| !opts
+            .env
+            .exists(&FileType::Hint.get_full_filepath(path.clone(), *x))));
+        assert!(reader.read_entry_at(0, true).is_ok());
+
+        drop(reader);
+        db.core.lock().unwrap().remove_obsolete_files();
+        assert!(!exists(inputs[0]));
+        drop(db);
+
+        // leftovers of a crash
+        let orphans = [
+            FileType::Log.get_full_filepath(path.clone(), 999),
+            FileType::Rewrite.get_full_filepath(path.clone(), 998),
+            FileType::Hint.get_full_filepath(path.clone(), 997),
+            FileType::Manifest.get_full_filepath(path.clone(), 996),
+            FileType::Temp.get_full_filepath(path.clone(), 995),
+        ];
+        for orphan in &orphans {
+            write_file(&opts, orphan, b"orphan");
+        }
+        let unknown = path.join("README");
+        write_file(&opts, &unknown, b"not ours");
+
+        let db = BitcaskDB::open(&path, opts.clone()).unwrap();
+        assert!(orphans.iter().all(|x| !opts.env.exists(x)));
+        assert!(opts.env.exists(&unknown));
+        assert_eq!(
+            db.get(ReadOptions::default(), b"key1").unwrap(),
+            Some(b"value".to_vec())
+        );
+        let manifests = opts
+            .env
+            .list(&path)
+            .unwrap()
+            .iter()
+            .filter(|x| matches!(FileType::parse_filename(x), Some((FileType::Manifest, _))))
+            .count();
+        assert_eq!(manifests, 1);
+    }
+
+    #[test]
+    fn test_row_cache() {
+        let (path, base) = prepare_db();
+        let opts = Options {
+            target_file_size: 64,
+            row_cache_size: 1 << 20,
+            ..base.clone()
+        };
+        let db = BitcaskDB::open(&path, opts).unwrap();
+        for i in 0..40 {
+            let key = format!("key{}", i % 4);
+            let value = format!("value{}", i);
+            db.put(WriteOptions::default(), key.as_bytes(), value.as_bytes())
+                .unwrap();
+        }
+        let no_fill = ReadOptions {
+            fill_cache: false,
+            ..Default::default()
+        };
+        let read = |opts: ReadOptions| db.get(opts, b"key1").unwrap();
+        assert_eq!(read(no_fill.clone()), Some(b"value37".to_vec()));
+        assert_eq!(read(ReadOptions::default()), Some(b"value37".to_vec()));
+        assert_eq!(read(ReadOptions::default()), Some(b"value37".to_vec()));
+        let stats = db.row_cache_stats();
+        assert_eq!((stats.hits, stats.misses), (1, 2));
+        assert!(stats.usage > 0);
+
+        // the cached values of the merged files go with them
+        db.compact().unwrap();
+        assert_eq!(db.row_cache_stats().usage, 0);
+        assert_eq!(read(ReadOptions::default()), Some(b"value37".to_vec()));
+        assert_eq!(db.row_cache_stats().misses, 3);
+    }
+
+    #[test]
+    fn test_snapshot_shares_read_state() {
+        let (path, base) = prepare_db();
+        let db = BitcaskDB::open(&path, base).unwrap();
+        let mut batch = WriteBatch::new();
+        for i in 0..1000 {
+            batch.put(format!("key{:04}", i).as_bytes(), b"value");
+        }
+        db.write(WriteOptions::default(), &batch).unwrap();
+
+        // taking a snapshot copies nothing
+        let snapshot = db.snapshot();
+        {
+            let live = db.read_state.read().unwrap();
+            assert!(snapshot.state().mem_index.ptr_eq(&live.mem_index));
+            assert!(Arc::ptr_eq(&snapshot.state().files, &live.files));
+        }
+
+        // the writes after it leave it as it was
+        db.put(WriteOptions::default(), b"key0000", b"new").unwrap();
+        db.delete(WriteOptions::default(), b"key0001").unwrap();
+        db.put(WriteOptions::default(), b"key1000", b"value")
+            .unwrap();
+        {
+            let live = db.read_state.read().unwrap();
+            assert!(!snapshot.state().mem_index.ptr_eq(&live.mem_index));
+            assert!(Arc::ptr_eq(&snapshot.state().files, &live.files));
+        }
+        let at_snapshot = ReadOptions {
+            snapshot: Some(snapshot.clone()),
+            ..Default::default()
+        };
+        assert_eq!(snapshot.state().mem_index.len(), 1000);
+        for key in [&b"key0000"[..], b"key0001", b"key0999"] {
+            assert_eq!(
+                db.get(at_snapshot.clone(), key).unwrap(),
+                Some(b"value".to_vec())
+            );
+        }
+        assert_eq!(db.get(at_snapshot, b"key1000").unwrap(), None);
+        assert_eq!(
+            db.get(ReadOptions::default(), b"key0000").unwrap(),
+            Some(b"new".to_vec())
+        );
+        assert_eq!(db.get(ReadOptions::default(), b"key0001").unwrap(), None);
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let (path, base) = prepare_db();
+        let opts = Options {
+            target_file_size: 64,
+            ..base.clone()
+        };
+        let db = BitcaskDB::open(&path, opts.clone()).unwrap();
+        for i in 0..20 {
+            let key = format!("key{}", i % 4);
+            let value = format!("value{}", i);
+            db.put(WriteOptions::default(), key.as_bytes(), value.as_bytes())
+                .unwrap();
+        }
+        let snapshot = db.snapshot();
+        let at_snapshot = ReadOptions {
+            snapshot: Some(snapshot.clone()),
+            ..Default::default()
+        };
+
+        db.put(WriteOptions::default(), b"key0", b"changed")
+            .unwrap();
+        db.delete(WriteOptions::default(), b"key1").unwrap();
+        db.put(WriteOptions::default(), b"key9", b"new").unwrap();
+        let inputs = db.freeze_file_ids();
+        db.compact().unwrap();
+
+        let read = |opts: &ReadOptions, key: &[u8]| db.get(opts.clone(), key).unwrap();
+        assert_eq!(read(&at_snapshot, b"key0"), Some(b"value16".to_vec()));
+        assert_eq!(read(&at_snapshot, b"key1"), Some(b"value17".to_vec()));
+        assert_eq!(read(&at_snapshot, b"key9"), None);
+        assert_eq!(
+            read(&ReadOptions::default(), b"key0"),
+            Some(b"changed".to_vec())
+        );
+        assert_eq!(read(&ReadOptions::default(), b"key1"), None);
+
+        let mut iter = db.iter(at_snapshot.clone());
+        iter.seek_to_first();
+        let mut kvs = vec![];
+        while iter.valid() {
+            kvs.push((iter.key().to_vec(), iter.value().unwrap()));
+            iter.next();
+        }
+        let expected: Vec<_> = (16..20)
+            .map(|i| {
+                (
+                    format!("key{}", i % 4).into_bytes(),
+                    format!("value{}", i).into_bytes(),
+                )
+            })
+            .collect();
+        assert_eq!(kvs, expected);
+
+        // the merged files are kept for the snapshot only
+        let exists = |id: u64| {
+            let log = FileType::Log.get_full_filepath(path.clone(), id);
+            opts.env.exists(&log)
+        };
+        assert!(inputs.iter().any(|x| exists(*x)));
+        drop((snapshot, at_snapshot, iter));
+        db.core.lock().unwrap().remove_obsolete_files();
+        assert!(inputs.iter().all(|x| !exists(*x)));
+    }
+
+    #[test]
+    fn test_ttl() {
+        let (path, base) = prepare_db();
+        let opts = Options {
+            target_file_size: 32,
+            ..base.clone()
+        };
+        let ttl = std::time::Duration::from_millis(100);
+        let db = BitcaskDB::open(&path, opts.clone()).unwrap();
+        db.put(WriteOptions::default(), b"token", b"old").unwrap();
+        db.put(WriteOptions::default(), b"filler0", b"v").unwrap();
+        db.put_with_ttl(WriteOptions::default(), b"token", b"new", ttl)
+            .unwrap();
+        let mut batch = WriteBatch::new();
+        batch.put_with_ttl(b"session", b"s", ttl);
+        batch.put(b"filler1", b"v");
+        db.write(WriteOptions::default(), &batch).unwrap();
+        let token_file = {
+            let read_state = db.read_state.read().unwrap();
+            read_state.mem_index[b"token".as_slice()].handle.file_id
+        };
+        db.put(WriteOptions::default(), b"filler2", b"v").unwrap();
+
+        let read = |db: &BitcaskDB, key: &[u8]| db.get(ReadOptions::default(), key).unwrap();
+        let keys = |db: &BitcaskDB| {
+            let mut iter = db.iter(ReadOptions::default());
+            iter.seek_to_first();
+            let mut keys = vec![];
+            while iter.valid() {
+                keys.push(String::from_utf8(iter.key().to_vec()).unwrap());
+                iter.next();
+            }
+            keys
+        };
+        assert_eq!(read(&db, b"token"), Some(b"new".to_vec()));
+        assert_eq!(read(&db, b"session"), Some(b"s".to_vec()));
+        assert_eq!(keys(&db).len(), 5);
+
+        std::thread::sleep(ttl);
+        assert_eq!(read(&db, b"token"), None);
+        assert_eq!(read(&db, b"session"), None);
+        assert_eq!(keys(&db), ["filler0", "filler1", "filler2"]);
+
+        // merging the expired put alone must not bring the old one back
+        assert!(db.freeze_file_ids()[0] < token_file);
+        db.compact_files(&[token_file]).unwrap();
+        drop(db);
+        let db = BitcaskDB::open(&path, opts.clone()).unwrap();
+        assert_eq!(read(&db, b"token"), None);
+
+        // a full merge drops the expired keys from the index
+        db.compact().unwrap();
+        assert_eq!(db.read_state.read().unwrap().mem_index.len(), 3);
+        drop(db);
+        let db = BitcaskDB::open(&path, opts).unwrap();
+        assert_eq!(read(&db, b"token"), None);
+        assert_eq!(keys(&db), ["filler0", "filler1", "filler2"]);
+    }
+
+    #[test]
+    fn test_huge_ttl() {
+        let (path, base) = prepare_db();
+        let db = BitcaskDB::open(&path, base).unwrap();
+        // just over u64::MAX millis, which would wrap to 384
+        let over_u64 = std::time::Duration::from_secs(18_446_744_073_709_552);
+        for (key, ttl) in [(b"k1", std::time::Duration::MAX), (b"k2", over_u64)] {
+            db.put_with_ttl(WriteOptions::default(), key, b"value", ttl)
+                .unwrap();
+            // saturates instead of wrapping to an early expiry
+            let expire_at = db.read_state.read().unwrap().mem_index[key.as_slice()].expire_at;
+            assert_eq!(expire_at, u64::MAX);
+            assert_eq!(
+                db.get(ReadOptions::default(), key).unwrap(),
+                Some(b"value".to_vec())
+            );
+        }
+    }
+
+    #[test]
+    fn test_sequence_and_meta() {
+        let (path, base) = prepare_db();
+        let before = crate::model::now_millis();
+        let db = BitcaskDB::open(&path, base.clone()).unwrap();
+        db.put(WriteOptions::default(), b"k1", b"v1").unwrap();
+        db.put(WriteOptions::default(), b"k2", b"v2").unwrap();
+        db.put(WriteOptions::default(), b"k1", b"v1-new").unwrap();
+        let meta = |db: &BitcaskDB, key: &[u8]| {
+            db.get_with_meta(ReadOptions::default(), key)
+                .unwrap()
+                .map(|(_, meta)| meta)
+        };
+        let (value, m1) = db
+            .get_with_meta(ReadOptions::default(), b"k1")
+            .unwrap()
+            .unwrap();
+        assert_eq!(value, b"v1-new");
+        let m2 = meta(&db, b"k2").unwrap();
+        assert!(m2.sequence < m1.sequence);
+        assert!(m1.timestamp >= before && m1.timestamp <= crate::model::now_millis());
+        assert_eq!(meta(&db, b"k3"), None);
+
+        // the hint of the frozen file carries the write times too
+        db.core.lock().unwrap().prepare_new_active_file().unwrap();
+        let file_id = db.freeze_file_ids()[0];
+        let hint = crate::hint::read_hint_file(&*base.env, path.clone(), file_id)
+            .unwrap()
+            .unwrap();
+        for (key, m) in [(&b"k1"[..], m1), (b"k2", m2)] {
+            let e = hint.iter().find(|x| x.key == key).unwrap();
+            assert_eq!((e.seq, e.ts), (m.sequence, m.timestamp));
+        }
+
+        // sequences go on after the records holding the last ones are gone
+        db.delete(WriteOptions::default(), b"k1").unwrap();
+        db.delete(WriteOptions::default(), b"k2").unwrap();
+        db.core.lock().unwrap().prepare_new_active_file().unwrap();
+        db.compact().unwrap();
+        drop(db);
+        let db = BitcaskDB::open(&path, base.clone()).unwrap();
+        db.put(WriteOptions::default(), b"k3", b"v3").unwrap();
+        assert!(meta(&db, b"k3").unwrap().sequence > m1.sequence + 2);
+    }
+
+    #[test]
+    fn test_recover_by_sequence() {
+        let mut read_state = super::ReadState::default();
+        let mut deleted = HashMap::new();
+        let entry = |seq: u64| super::IndexEntry {
+            handle: super::EntryHandle {
+                file_id: 1,
+                offset: seq,
+                length: 1,
+            },
+            seq,
+            expire_at: 0,
+        };
+        let mut recover = |op_type, key: &[u8], seq| {
+            read_state.recover(op_type, key.to_vec(), entry(seq), &mut deleted);
+            read_state.mem_index.get(key).map(|x| x.seq)
+        };
+        // an older put replayed after a newer one
+        assert_eq!(recover(OpType::Put, b"a", 5), Some(5));
+        assert_eq!(recover(OpType::Put, b"a", 3), Some(5));
+        assert_eq!(recover(OpType::Del, b"a", 4), Some(5));
+        // an older put replayed after a newer deletion
+        assert_eq!(recover(OpType::Del, b"b", 7), None);
+        assert_eq!(recover(OpType::Put, b"b", 6), None);
+        assert_eq!(recover(OpType::Put, b"b", 8), Some(8));
+    }
+
+    #[test]
+    fn test_lock_db() {
+        let (path, base) = prepare_db();
+        let db = BitcaskDB::open(&path, base.clone()).unwrap();
+        db.put(WriteOptions::default(), b"k1", b"v1").unwrap();
+        match BitcaskDB::open(&path, base.clone()) {
+            Err(DBError::Locked(_)) => {}
+            r => panic!("unexpected {:?}", r.map(|_| ())),
+        }
+
+        // the LOCK file stays locked while the db is open
+        let lock_path = FileType::Lock.get_full_filepath(path.clone(), 0);
+        assert!(base.env.lock(&lock_path).is_err());
+
+        drop(db);
+        let db = BitcaskDB::open(&path, base.clone()).unwrap();
+        assert_eq!(
+            db.get(ReadOptions::default(), b"k1").unwrap(),
+            Some(b"v1".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_create_if_missing_error_if_exists() {
+        let (path, base) = prepare_db();
+        let no_create = Options {
+            create_if_missing: false,
+            ..base.clone()
+        };
+        match BitcaskDB::open(&path, no_create.clone()) {
+            Err(DBError::NotFound(_)) => {}
+            r => panic!("unexpected {:?}", r.map(|_| ())),
+        }
+        assert!(!base.env.exists(&path));
+
+        // an empty directory is not a db, it is left untouched
+        base.env.create_dir_all(&path).unwrap();
+        match BitcaskDB::open(&path, no_create.clone()) {
+            Err(DBError::NotFound(_)) => {}
+            r => panic!("unexpected {:?}", r.map(|_| ())),
+        }
+        assert!(base.env.list(&path).unwrap().is_empty());
+
+        let error_if_exists = Options {
+            error_if_exists: true,
+            ..base.clone()
+        };
+        let db = BitcaskDB::open(&path, error_if_exists.clone()).unwrap();
+        db.put(WriteOptions::default(), b"k1", b"v1").unwrap();
+        drop(db);
+        assert!(base
+            .env
+            .exists(&FileType::Current.get_full_filepath(path.clone(), 0)));
+
+        match BitcaskDB::open(&path, error_if_exists) {
+            Err(DBError::AlreadyExists(_)) => {}
+            r => panic!("unexpected {:?}", r.map(|_| ())),
+        }
+        let db = BitcaskDB::open(&path, no_create).unwrap();
+        assert_eq!(
+            db.get(ReadOptions::default(), b"k1").unwrap(),
+            Some(b"v1".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_concurrent_put_get() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<BitcaskDB>();
+
+        let (path, base) = prepare_db();
+        let opts = Options {
+            target_file_size: 4096,
+            ..base.clone()
+        };
+        let db = std::sync::Arc::new(BitcaskDB::open(&path, opts.clone()).unwrap());
+        let threads: Vec<_> = (0..8)
+            .map(|t| {
+                let db = db.clone();
+                std::thread::spawn(move || {
+                    for i in 0..500 {
+                        let key = format!("t{}-key{}", t, i % 50);
+                        let value = format!("value{}", i);
+                        db.put(WriteOptions::default(), key.as_bytes(), value.as_bytes())
+                            .unwrap();
+                        let read = db.get(ReadOptions::default(), key.as_bytes()).unwrap();
+                        assert_eq!(read, Some(value.into_bytes()));
+                    }
+                })
+            })
+            .collect();
+        let compactor = {
+            let db = db.clone();
+            std::thread::spawn(move || {
+                for _ in 0..5 {
+                    db.compact().unwrap();
+                }
+            })
+        };
+        for t in threads {
+            t.join().unwrap();
+        }
+        compactor.join().unwrap();
+
+        let check = |db: &BitcaskDB| {
+            for t in 0..8 {
+                for i in 450..500 {
+                    let key = format!("t{}-key{}", t, i % 50);
+                    assert_eq!(
+                        db.get(ReadOptions::default(), key.as_bytes()).unwrap(),
+                        Some(format!("value{}", i).into_bytes())
+                    );
+                }
+            }
+        };
+        check(&db);
+        drop(db);
+        check(&BitcaskDB::open(&path, opts).unwrap());
+    }
+
+    #[test]
+    fn test_group_writers() {
+        let (path, base) = prepare_db();
+        let db = BitcaskDB::open(&path, base.clone()).unwrap();
+        let mut core = db.core.lock().unwrap();
+        let mut small = WriteBatch::new();
+        small.put(b"k", b"v");
+        let mut large = WriteBatch::new();
+        large.put(b"k", &vec![0; MAX_GROUP_SIZE / 2]);
+        let mut queue = |batches: &[(&WriteBatch, bool)]| {
+            core.writers = batches
+                .iter()
+                .enumerate()
+                .map(|(i, (batch, sync))| Writer {
+                    id: i as u64,
+                    batch: NonNull::from(*batch),
+                    sync: *sync,
+                })
+                .collect();
+            core.group_writers()
+        };
+
+        assert_eq!(
+            queue(&[(&small, true), (&small, false), (&small, true)]),
+            (3, true)
+        );
+        // a group which is not synced leaves the synced write to the next one
+        assert_eq!(
+            queue(&[(&small, false), (&small, false), (&small, true)]),
+            (2, false)
+        );
+        // a small write does not wait for a large one
+        assert_eq!(queue(&[(&small, false), (&large, false)]), (1, false));
+        assert_eq!(
+            queue(&[(&large, false), (&small, false), (&large, false)]),
+            (2, false)
+        );
+        assert_eq!(queue(&[(&large, true), (&large, true)]), (1, true));
+    }
+
+    #[test]
+    fn test_group_commit() {
+        let (path, base) = prepare_db();
+        let opts = Options {
+            target_file_size: 64 << 10,
+            ..base.clone()
+        };
+        let db = std::sync::Arc::new(BitcaskDB::open(&path, opts.clone()).unwrap());
+        let threads: Vec<_> = (0..8)
+            .map(|t| {
+                let db = db.clone();
+                std::thread::spawn(move || {
+                    for i in 0..100 {
+                        let mut batch = WriteBatch::new();
+                        for j in 0..=(i % 3) {
+                            let key = format!("t{}-key{}-{}", t, i, j);
+                            batch.put(key.as_bytes(), key.as_bytes());
+                        }
+                        let options = WriteOptions { sync: t % 2 == 0 };
+                        db.write(options, &batch).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert!(db.core.lock().unwrap().writers.is_empty());
+        assert!(db.core.lock().unwrap().write_results.is_empty());
+
+        let check = |db: &BitcaskDB| {
+            let mut seqs = vec![];
+            for t in 0..8 {
+                for i in 0..100 {
+                    for j in 0..=(i % 3) {
+                        let key = format!("t{}-key{}-{}", t, i, j);
+                        let (value, meta) = db
+                            .get_with_meta(ReadOptions::default(), key.as_bytes())
+                            .unwrap()
+                            .unwrap();
+                        assert_eq!(value, key.as_bytes());
+                        seqs.push(meta.sequence);
+                    }
+                }
+            }
+            seqs.sort_unstable();
+            assert_eq!(seqs, (1..=seqs.len() as u64).collect::<Vec<_>>());
+        };
+        check(&db);
+        drop(db);
+        check(&BitcaskDB::open(&path, opts).unwrap());
+    }
+
+    #[test]
+    fn test_sync_policy() {
+        let (path, base) = prepare_db();
+        let write_n = |policy: SyncPolicy, n: usize| {
+            let opts = Options {
+                sync_policy: policy,
+                ..base.clone()
+            };
+            let db = BitcaskDB::open(&path, opts).unwrap();
+            for i in 0..n {
+                let key = format!("key{}", i);
+                db.put(WriteOptions::default(), key.as_bytes(), b"value")
+                    .unwrap();
+            }
+            db
+        };
+        let record_size = (ENTRY_HEADER_SIZE + "key0".len() + 1 + "value".len()) as u64;
+
+        let stats = write_n(SyncPolicy::Never, 5).sync_stats();
+        assert_eq!(
+            (stats.writes, stats.syncs, stats.unsynced_writes),
+            (5, 0, 5)
+        );
+        assert_eq!(stats.unsynced_bytes, 5 * record_size);
+        let db = write_n(SyncPolicy::Never, 1);
+        db.put(WriteOptions { sync: true }, b"key", b"value")
+            .unwrap();
+        assert_eq!(db.sync_stats().syncs, 1);
+        assert_eq!(db.sync_stats().unsynced_writes, 0);
+        drop(db);
+
+        let stats = write_n(SyncPolicy::Always, 5).sync_stats();
+        assert_eq!(
+            (stats.writes, stats.syncs, stats.unsynced_writes),
+            (5, 5, 0)
+        );
+        let stats = write_n(SyncPolicy::EveryN(3), 8).sync_stats();
+        assert_eq!((stats.syncs, stats.unsynced_writes), (2, 2));
+        let stats = write_n(SyncPolicy::BytesWritten(record_size * 4), 10).sync_stats();
+        assert_eq!((stats.syncs, stats.unsynced_writes), (2, 2));
+        assert_eq!(stats.bytes_written, 10 * record_size);
+
+        let interval = std::time::Duration::from_millis(10);
+        let db = write_n(SyncPolicy::EveryInterval(interval), 5);
+        let start = std::time::Instant::now();
+        while db.sync_stats().syncs == 0 {
+            assert!(start.elapsed() < std::time::Duration::from_secs(10));
+            std::thread::sleep(interval);
+        }
+        assert_eq!(db.sync_stats().unsynced_writes, 0);
+        // the thread is stopped with the db, which opens again
+        drop(db);
+        assert_eq!(
+            write_n(SyncPolicy::Never, 0).sync_stats(),
+            Default::default()
+        );
+        let opts = Options {
+            sync_policy: SyncPolicy::EveryInterval(std::time::Duration::ZERO),
+            ..base.clone()
+        };
+        assert!(matches!(
+            BitcaskDB::open(&path, opts),
+            Err(DBError::InvalidArgument(_))
+        ));
+
+        // the writes of a frozen file are synced with it
+        let opts = Options {
+            target_file_size: record_size * 2,
+            sync_policy: SyncPolicy::EveryN(100),
+            ..base.clone()
+        };
+        let db = BitcaskDB::open(&path, opts).unwrap();
+        for i in 0..3 {
+            db.put(
+                WriteOptions::default(),
+                format!("key{}", i).as_bytes(),
+                b"value",
+            )
+            .unwrap();
+        }
+        assert_eq!(
+            (db.sync_stats().syncs, db.sync_stats().unsynced_writes),
+            (1, 1)
+        );
+    }
+
+    #[test]
+    fn test_flush_and_close() {
+        let (path, base) = prepare_db();
+        let active_file_id = |db: &BitcaskDB| {
+            let core = db.core.lock().unwrap();
+            core.active_file.as_ref().unwrap().get_file_id()
+        };
+        let hint_exists = |file_id| {
+            let hint = FileType::Hint.get_full_filepath(path.clone(), file_id);
+            base.env.exists(&hint)
+        };
+        let db = BitcaskDB::open(&path, base.clone()).unwrap();
+        db.put(WriteOptions::default(), b"k1", b"v1").unwrap();
+        let file_id = active_file_id(&db);
+        db.flush_all().unwrap();
+        assert_eq!(db.sync_stats().unsynced_writes, 0);
+        assert_eq!(active_file_id(&db), file_id);
+        assert!(!hint_exists(file_id));
+        drop(db);
+
+        let opts = Options {
+            hint_on_flush: true,
+            ..base.clone()
+        };
+        let db = BitcaskDB::open(&path, opts.clone()).unwrap();
+        db.put(WriteOptions::default(), b"k2", b"v2").unwrap();
+        let file_id = active_file_id(&db);
+        db.flush_all().unwrap();
+        assert!(hint_exists(file_id));
+        assert!(db.freeze_file_ids().contains(&file_id));
+
+        db.put(WriteOptions::default(), b"k3", b"v3").unwrap();
+        let file_id = active_file_id(&db);
+        db.close().unwrap();
+        // frozen with its hint, and the LOCK is released
+        assert!(hint_exists(file_id));
+        let db = BitcaskDB::open(&path, opts.clone()).unwrap();
+        assert!(db.freeze_file_ids().contains(&file_id));
+        for (key, value) in [(b"k1", b"v1"), (b"k2", b"v2"), (b"k3", b"v3")] {
+            assert_eq!(
+                db.get(ReadOptions::default(), key).unwrap(),
+                Some(value.to_vec())
+            );
+        }
+        db.close().unwrap();
+
+        // without writes, the empty active file is kept and reused
+        let files = || {
+            let mut files: Vec<_> = base
+                .env
+                .list(&path)
+                .unwrap()
+                .into_iter()
+                .map(|x| (file_len(&base, &path.join(&x)), x))
+                .collect();
+            files.sort();
+            files
+        };
+        let before = files();
+        for _ in 0..3 {
+            BitcaskDB::open(&path, opts.clone())
+                .unwrap()
+                .close()
+                .unwrap();
+        }
+        assert_eq!(files(), before);
+
+        // a latched error only releases the LOCK
+        let db = BitcaskDB::open(&path, opts.clone()).unwrap();
+        db.put(WriteOptions::default(), b"k4", b"v4").unwrap();
+        let file_id = active_file_id(&db);
+        db.core
+            .lock()
+            .unwrap()
+            .set_bg_error(from_io_error(std::io::Error::from_raw_os_error(5)));
+        assert_eq!(db.close().unwrap_err().kind(), ErrorKind::Background);
+        assert!(!hint_exists(file_id));
+        let db = BitcaskDB::open(&path, opts).unwrap();
+        assert_eq!(
+            db.get(ReadOptions::default(), b"k4").unwrap(),
+            Some(b"v4".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_first_open_fails_before_current() {
+        let env = FaultEnv::new(Arc::new(MemEnv::new()));
+        let opts = Options {
+            env: Arc::new(env.clone()),
+            ..Default::default()
+        };
+        // the rename of CURRENT, after the manifest is written
+        env.fail_nth(FaultOp::Rename, 1);
+        assert!(BitcaskDB::open("/db", opts.clone()).is_err());
+
+        // still a new db
+        let db = BitcaskDB::open("/db", opts.clone()).unwrap();
+        db.put(WriteOptions::default(), b"key", b"value").unwrap();
+        drop(db);
+        let db = BitcaskDB::open("/db", opts).unwrap();
+        assert_eq!(
+            db.get(ReadOptions::default(), b"key").unwrap(),
+            Some(b"value".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_rotation_syncs_frozen_file() {
+        let env = FaultEnv::new(Arc::new(MemEnv::new()));
+        let opts = Options {
+            target_file_size: 128,
+            sync_policy: SyncPolicy::Never,
+            env: Arc::new(env.clone()),
+            ..Default::default()
+        };
+        let db = BitcaskDB::open("/db", opts.clone()).unwrap();
+        for i in 0..20 {
+            let key = format!("key{}", i);
+            db.put(WriteOptions::default(), key.as_bytes(), b"value")
+                .unwrap();
+        }
+        let frozen = db.freeze_file_ids();
+        assert!(frozen.len() > 2);
+        let keys: Vec<_> = {
+            let read_state = db.read_state.read().unwrap();
+            read_state
+                .mem_index
+                .iter()
+                .filter(|(_, x)| frozen.contains(&x.handle.file_id))
+                .map(|(key, _)| key.clone())
+                .collect()
+        };
+        env.power_cut();
+        drop(db);
+        env.power_on();
+
+        // the writes before the last rotation are synced with their file
+        let db = BitcaskDB::open("/db", opts).unwrap();
+        for key in &keys {
+            assert_eq!(
+                db.get(ReadOptions::default(), key).unwrap(),
+                Some(b"value".to_vec())
+            );
+        }
+    }
+
+    #[test]
+    fn test_freeze_does_not_read_back() {
+        let env = FaultEnv::new(Arc::new(MemEnv::new()));
+        let opts = Options {
+            hint_on_flush: true,
+            env: Arc::new(env.clone()),
+            ..Default::default()
+        };
+        let db = BitcaskDB::open("/db", opts.clone()).unwrap();
+        for i in 0..100 {
+            let key = format!("key{}", i % 10);
+            db.put(WriteOptions::default(), key.as_bytes(), b"value")
+                .unwrap();
+        }
+        db.delete(WriteOptions::default(), b"key0").unwrap();
+        // the hint comes from the records collected as they were written
+        let reads = env.count(FaultOp::Read);
+        db.flush_all().unwrap();
+        assert_eq!(env.count(FaultOp::Read), reads);
+        db.compact().unwrap();
+        db.put(WriteOptions::default(), b"key10", b"value").unwrap();
+        let reads = env.count(FaultOp::Read);
+        db.close().unwrap();
+        assert_eq!(env.count(FaultOp::Read), reads);
+
+        // the hints of the logs and of the rewrite file are complete
+        let db = BitcaskDB::open("/db", opts.clone()).unwrap();
+        let core = db.core.lock().unwrap();
+        assert!(core.freeze_files.len() > 1);
+        for file_id in core.freeze_files.keys() {
+            let hint = FileType::Hint.get_full_filepath(PathBuf::from("/db"), *file_id);
+            assert!(opts.env.exists(&hint), "{}", hint.display());
+        }
+        drop(core);
+        assert_eq!(count_kvs(&db, 11), 10);
+        assert_eq!(db.get(ReadOptions::default(), b"key0").unwrap(), None);
+    }
+
+    #[test]
+    fn test_bg_error_and_resume() {
+        let (path, base) = prepare_db();
+        let db = BitcaskDB::open(&path, base.clone()).unwrap();
+        db.put(WriteOptions::default(), b"k1", b"v1").unwrap();
+
+        // a group write torn by a failure of the disk
+        let file = {
+            let mut core = db.core.lock().unwrap();
+            let file = core.active_file.clone().unwrap();
+            core.failed_write_offset = Some(file.get_offset());
+            file.append(b"torn write").unwrap();
+            core.set_bg_error(from_io_error(std::io::Error::from_raw_os_error(5)));
+            file
+        };
+        let good_len = file.get_offset() - b"torn write".len() as u64;
+        let e = db.put(WriteOptions::default(), b"k2", b"v2").unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::Background);
+        assert_eq!(db.flush_all().unwrap_err().kind(), ErrorKind::Background);
+        assert_eq!(db.compact().unwrap_err().kind(), ErrorKind::Background);
+        assert_eq!(
+            db.get(ReadOptions::default(), b"k1").unwrap(),
+            Some(b"v1".to_vec())
+        );
+        assert_eq!(db.get(ReadOptions::default(), b"k2").unwrap(), None);
+
+        db.resume().unwrap();
+        assert_eq!(file.get_offset(), good_len);
+        let log_path = FileType::Log.get_full_filepath(path.clone(), file.get_file_id());
+        assert_eq!(file_len(&base, &log_path), good_len);
+        assert!(db.freeze_file_ids().contains(&file.get_file_id()));
+        db.put(WriteOptions::default(), b"k2", b"v2").unwrap();
+        drop(db);
+
+        // the cut file is sound, it opens with the default policy
+        let db = BitcaskDB::open(&path, base.clone()).unwrap();
+        for (key, value) in [(b"k1", b"v1"), (b"k2", b"v2")] {
+            assert_eq!(
+                db.get(ReadOptions::default(), key).unwrap(),
+                Some(value.to_vec())
+            );
+        }
+
+        // a corruption can not be resumed
+        db.core
+            .lock()
+            .unwrap()
+            .set_bg_error(corruption("bad record"));
+        assert_eq!(db.resume().unwrap_err().kind(), ErrorKind::Background);
+        assert!(db.delete(WriteOptions::default(), b"k1").is_err());
+        assert_eq!(
+            db.get(ReadOptions::default(), b"k1").unwrap(),
+            Some(b"v1".to_vec())
+        );
+        // the LOCK is released anyway
+        assert!(db.close().is_err());
+        BitcaskDB::open(&path, base.clone()).unwrap();
+    }
+
+    #[test]
+    fn test_get_without_core_lock() {
+        let (path, base) = prepare_db();
+        let opts = Options {
+            target_file_size: 256,
+            ..base.clone()
+        };
+        let db = BitcaskDB::open(&path, opts).unwrap();
+        for i in 0..20 {
+            let key = format!("key{}", i);
+            let value = format!("value{}", i);
+            db.put(WriteOptions::default(), key.as_bytes(), value.as_bytes())
+                .unwrap();
+        }
+
+        // a writer stuck in file io under the core lock does not block reads
+        let core = db.core.lock().unwrap();
+        for i in 0..20 {
+            let key = format!("key{}", i);
+            assert_eq!(
+                db.get(ReadOptions::default(), key.as_bytes()).unwrap(),
+                Some(format!("value{}", i).into_bytes())
+            );
+        }
+        assert_eq!(db.get(ReadOptions::default(), b"missing").unwrap(), None);
+        drop(core);
+    }
+
+    type KvOps = Vec<(Vec<u8>, Option<Vec<u8>>)>;
+
+    /// Synced writes over a few keys, with rotations, manifest rolls and
+    /// compactions in between. Stops at the first failed write, returns
+    /// the state left by the acknowledged writes and the failed batch.
+    fn crash_workload(db: &BitcaskDB) -> (HashMap<Vec<u8>, Vec<u8>>, Option<KvOps>) {
+        let mut acked = HashMap::new();
+        for i in 0..40 {
+            let mut ops: KvOps = vec![(
+                format!("key{}", i % 7).into_bytes(),
+                Some(format!("value{}", i).into_bytes()),
+            )];
+            if i % 5 == 0 {
+                ops.push((format!("key{}", (i + 3) % 7).into_bytes(), None));
+            }
+            let mut batch = WriteBatch::new();
+            for (key, value) in &ops {
+                match value {
+                    Some(value) => batch.put(key, value),
+                    None => batch.delete(key),
+                }
+            }
+            if db.write(WriteOptions { sync: true }, &batch).is_err() {
+                return (acked, Some(ops));
+            }
+            for (key, value) in ops {
+                match value {
+                    Some(value) => acked.insert(key, value),
+                    None => acked.remove(&key),
+                };
+            }
+            if i % 15 == 14 {
+                // a failed compaction leaves the db as it was
+                let _ = db.compact();
+            }
+        }
+        (acked, None)
+    }
+
+    /// Run `crash_workload` failing the n-th `op` for every n it reaches,
+    /// cut the power after it and check that the reopened db has every
+    /// acknowledged write, and the failed batch either whole or not at all.
+    fn crash_test(op: FaultOp) {
+        for n in 1.. {
+            let env = FaultEnv::new(Arc::new(MemEnv::new()));
+            let opts = Options {
+                target_file_size: 128,
+                max_manifest_file_size: 512,
+                env: Arc::new(env.clone()),
+                ..Default::default()
+            };
+            let db = BitcaskDB::open("/db", opts.clone()).unwrap();
+            let start = env.count(op);
+            env.fail_nth(op, n);
+            let (acked, failed) = crash_workload(&db);
+            let reached = env.count(op) - start >= n;
+            env.power_cut();
+            drop(db);
+            env.power_on();
+
+            let db = BitcaskDB::open("/db", opts.clone())
+                .unwrap_or_else(|e| panic!("reopen after failing {:?} #{}: {:?}", op, n, e));
+            let read = |key: &[u8]| db.get(ReadOptions::default(), key).unwrap();
+            let failed = failed.unwrap_or_default();
+            for i in 0..7 {
+                let key = format!("key{}", i).into_bytes();
+                if failed.iter().all(|(x, _)| *x != key) {
+                    assert_eq!(read(&key), acked.get(&key).cloned(), "{:?} #{}", op, n);
+                }
+            }
+            let applied = failed.iter().all(|(key, value)| read(key) == *value);
+            let untouched = failed
+                .iter()
+                .all(|(key, _)| read(key) == acked.get(key).cloned());
+            assert!(applied || untouched, "{:?} #{} tore a batch", op, n);
+            db.put(WriteOptions { sync: true }, b"key0", b"again")
+                .unwrap();
+            if !reached {
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn test_crash_on_failed_write() {
+        crash_test(FaultOp::Write);
+    }
+
+    #[test]
+    fn test_crash_on_failed_sync() {
+        crash_test(FaultOp::Sync);
+    }
+
+    #[test]
+    fn test_crash_on_failed_rename() {
+        crash_test(FaultOp::Rename);
+    }
+}
@@ -1,12 +1,18 @@
-use crate::errors::{self, from_io_error, DBResult};
-use std::{cell::Cell, fs::File, io::ErrorKind, os::unix::prelude::FileExt};
+use crate::env::{Env, EnvFile, OpenMode};
+use crate::errors::{corruption_at, from_io_error, io_error, DBResult};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::{io::ErrorKind, path::Path};
 
-use crate::model::OwnedEntry;
+use crate::model::{
+    decode_entry_length, is_expired, BatchHeader, OpType, OwnedEntry, BATCH_HEADER_SIZE,
+    ENTRY_HEADER_SIZE,
+};
 
-pub(crate) type FileId = u64;
+/// The id of a log or rewrite file, see `BitcaskDB::freeze_file_ids`.
+pub type FileId = u64;
 pub(crate) const INVALID_FILE_ID: FileId = 0;
 
-#[derive(Debug, Clone, Copy, Default)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
 pub(crate) struct EntryHandle {
     pub(crate) file_id: FileId,
     pub(crate) offset: u64,
@@ -14,31 +20,101 @@ pub(crate) struct EntryHandle {
 }
 
 pub(crate) struct KeyAndEntryHandle {
+    pub(crate) op_type: OpType,
     pub(crate) key: Vec<u8>,
     pub(crate) handle: EntryHandle,
+    pub(crate) seq: u64,
+    pub(crate) ts: u64,
+    pub(crate) expire_at: u64,
 }
 
-pub(crate) struct EntryBlock {
-    data: Vec<u8>,
+/// What the index keeps for a key, the place of its latest put and when
+/// the put expires.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct IndexEntry {
+    pub(crate) handle: EntryHandle,
+    pub(crate) seq: u64,
+    /// milliseconds since the unix epoch, 0 if the put never expires.
+    pub(crate) expire_at: u64,
+}
+
+impl IndexEntry {
+    pub(crate) fn is_expired(&self, now: u64) -> bool {
+        is_expired(self.expire_at, now)
+    }
+}
+
+/// The outcome of reading the record at a given offset of a log file.
+#[derive(Debug)]
+pub(crate) enum LogRecord {
+    Entry(OwnedEntry, EntryHandle),
+    /// The records of a `WriteBatch`, `length` covers the header too.
+    Batch {
+        entries: Vec<(OwnedEntry, EntryHandle)>,
+        length: u64,
+    },
+    /// No more data at the offset.
+    Eof,
+    /// The record runs past the end of file, usually a torn write.
+    Incomplete,
+    /// The record is complete but can not be decoded.
+    Corrupted {
+        length: u64,
+    },
+}
+
+impl LogRecord {
+    /// The records read and the bytes they take, `None` if there are none.
+    pub(crate) fn into_entries(self) -> Option<(Vec<(OwnedEntry, EntryHandle)>, u64)> {
+        match self {
+            LogRecord::Entry(entry, handle) => Some((vec![(entry, handle)], handle.length)),
+            LogRecord::Batch { entries, length } => Some((entries, length)),
+            _ => None,
+        }
+    }
 }
 
 pub(crate) struct LogFile {
     id: FileId,
-    file: File,
-    offset: Cell<u64>, // write posistion
+    file: Box<dyn EnvFile>,
+    /// write posistion, only advanced by a single writer at a time, the
+    /// leader of the writer queue for the active file, readers use it to
+    /// bound their reads.
+    offset: AtomicU64,
 }
 
 impl LogFile {
-    pub fn new(id: FileId, file: File) -> LogFile {
+    pub fn new(id: FileId, file: Box<dyn EnvFile>) -> LogFile {
         LogFile {
-            id: id,
-            file: file,
-            offset: Cell::new(0),
+            id,
+            file,
+            offset: AtomicU64::new(0),
         }
     }
 
+    /// Create a new empty log file for append.
+    pub fn create<P: AsRef<Path>>(env: &dyn Env, id: FileId, path: P) -> DBResult<LogFile> {
+        let file = env
+            .open(path.as_ref(), OpenMode::Truncate)
+            .map_err(|e| io_error(format!("create {}", path.as_ref().display()), e))?;
+        Ok(LogFile::new(id, file))
+    }
+
+    /// Open an existing log file, the write position is set to the end of file.
+    pub fn open<P: AsRef<Path>>(env: &dyn Env, id: FileId, path: P) -> DBResult<LogFile> {
+        let file = env
+            .open(path.as_ref(), OpenMode::Existing)
+            .map_err(|e| io_error(format!("open {}", path.as_ref().display()), e))?;
+        let len = file.size().map_err(from_io_error)?;
+        Ok(LogFile {
+            id,
+            file,
+            offset: AtomicU64::new(len),
+        })
+    }
+
     pub fn get_offset(&self) -> u64 {
-        self.offset.get()
+        self.offset.load(Ordering::Acquire)
     }
 
     pub fn get_file_id(&self) -> FileId {
@@ -46,15 +122,20 @@ impl LogFile {
     }
 
     pub fn sync(&self) -> DBResult<()> {
-        self.file.sync_all().map_err(|e| errors::from_io_error(e))
+        self.file.sync().map_err(from_io_error)
     }
 
     /// write_entry may write half-success and half-failure
     pub fn write_entry(&self, entry: &OwnedEntry) -> DBResult<EntryHandle> {
-        let data = entry.as_ref_entry().encode_to_bytes();
-        assert!(data.len() > 0);
+        self.append(&entry.as_ref_entry().encode_to_bytes())
+    }
+
+    /// Append encoded records at the end of file, returns where they are.
+    /// Either all of `data` is written or an error is returned.
+    pub(crate) fn append(&self, data: &[u8]) -> DBResult<EntryHandle> {
+        assert!(!data.is_empty());
 
-        let origin_offset = self.offset.get();
+        let origin_offset = self.get_offset();
         let mut nwrite = 0;
 
         while nwrite < data.len() {
@@ -64,9 +145,9 @@ impl LogFile {
                 Err(e) => return Err(from_io_error(e)),
             };
             if bytes == 0 {
-                break; // EOF
+                return Err(from_io_error(ErrorKind::WriteZero.into()));
             }
-            self.offset.update(|x| x + bytes as u64);
+            self.offset.fetch_add(bytes as u64, Ordering::AcqRel);
             nwrite += bytes;
         }
         Ok(EntryHandle {
@@ -79,39 +160,184 @@ impl LogFile {
     pub fn read_entry(&self, handle: EntryHandle, verify_checksum: bool) -> DBResult<OwnedEntry> {
         assert!(self.id == handle.file_id);
         let mut buf = vec![0_u8; handle.length as usize];
-        let nread = match self.file.read_at(buf.as_mut(), handle.offset) {
-            Ok(bytes) => bytes,
-            Err(e) => return Err(from_io_error(e)),
-        };
-
+        let nread = self.read_full_at(&mut buf, handle.offset)?;
         if nread as u64 != handle.length {
-            todo!("");
+            return Err(corruption_at("entry truncated", self.id, handle.offset));
         }
         OwnedEntry::decode_from_bytes(&buf, verify_checksum)
+            .map_err(|e| e.at(self.id, handle.offset))
+    }
+
+    /// Read the record located at `offset`, used to scan the whole file
+    /// sequentially. Only io failures are returned as errors.
+    pub fn read_entry_at(&self, offset: u64, verify_checksum: bool) -> DBResult<LogRecord> {
+        let mut header = [0_u8; ENTRY_HEADER_SIZE];
+        match self.read_full_at(&mut header, offset)? {
+            0 => return Ok(LogRecord::Eof),
+            n if n < ENTRY_HEADER_SIZE => return Ok(LogRecord::Incomplete),
+            _ => {}
+        }
+        let length = decode_entry_length(&header);
+        if offset + length > self.get_offset() {
+            return Ok(LogRecord::Incomplete);
+        }
+        let mut buf = vec![0_u8; length as usize];
+        if self.read_full_at(&mut buf, offset)? < buf.len() {
+            return Ok(LogRecord::Incomplete);
+        }
+        match BatchHeader::decode(&buf, verify_checksum) {
+            Ok(Some(header)) => return self.read_batch_at(offset, header, verify_checksum),
+            Ok(None) => {}
+            Err(_) => return Ok(LogRecord::Corrupted { length }),
+        }
+        let handle = EntryHandle {
+            file_id: self.id,
+            offset,
+            length,
+        };
+        match OwnedEntry::decode_from_bytes(&buf, verify_checksum) {
+            Ok(entry) => Ok(LogRecord::Entry(entry, handle)),
+            Err(_) => Ok(LogRecord::Corrupted { length }),
+        }
+    }
+
+    /// Read the records framed by the batch header at `offset`, all of
+    /// them or none.
+    fn read_batch_at(
+        &self,
+        offset: u64,
+        header: BatchHeader,
+        verify_checksum: bool,
+    ) -> DBResult<LogRecord> {
+        let body_offset = offset + BATCH_HEADER_SIZE as u64;
+        let length = BATCH_HEADER_SIZE as u64 + header.length;
+        if offset + length > self.get_offset() {
+            return Ok(LogRecord::Incomplete);
+        }
+        let mut body = vec![0_u8; header.length as usize];
+        if self.read_full_at(&mut body, body_offset)? < body.len() {
+            return Ok(LogRecord::Incomplete);
+        }
+        if verify_checksum && crc32fast::hash(&body) != header.crc {
+            return Ok(LogRecord::Corrupted { length });
+        }
+
+        let mut entries = vec![];
+        let mut pos = 0;
+        while pos < body.len() {
+            if body.len() - pos < ENTRY_HEADER_SIZE {
+                return Ok(LogRecord::Corrupted { length });
+            }
+            let entry_length = decode_entry_length(&body[pos..]) as usize;
+            if body.len() - pos < entry_length {
+                return Ok(LogRecord::Corrupted { length });
+            }
+            let entry = match OwnedEntry::decode_from_bytes(
+                &body[pos..pos + entry_length],
+                verify_checksum,
+            ) {
+                Ok(entry) => entry,
+                Err(_) => return Ok(LogRecord::Corrupted { length }),
+            };
+            let handle = EntryHandle {
+                file_id: self.id,
+                offset: body_offset + pos as u64,
+                length: entry_length as u64,
+            };
+            entries.push((entry, handle));
+            pos += entry_length;
+        }
+        if entries.len() != header.count as usize {
+            return Ok(LogRecord::Corrupted { length });
+        }
+        Ok(LogRecord::Batch { entries, length })
     }
+
+    /// Whether the broken record at `offset` is the last one of the file,
+    /// as a torn write is. A length damaged in the middle of the file also
+    /// makes a record run to or past the end, but then the records after
+    /// it still decode. The body of a batch whose header is intact is not
+    /// searched, its own records are in there.
+    pub(crate) fn is_last_record(&self, offset: u64) -> DBResult<bool> {
+        let mut rest = vec![0_u8; self.get_offset().saturating_sub(offset) as usize];
+        let nread = self.read_full_at(&mut rest, offset)?;
+        rest.truncate(nread);
+        let start = match rest.get(..BATCH_HEADER_SIZE) {
+            Some(x) => match BatchHeader::decode(x, true) {
+                Ok(Some(header)) => BATCH_HEADER_SIZE as u64 + header.length,
+                _ => 1,
+            },
+            None => 1,
+        };
+        Ok((start..rest.len() as u64).all(|pos| !starts_with_record(&rest[pos as usize..])))
+    }
+
+    /// Drop everything after `len`, used to cut off a broken tail.
+    pub fn truncate(&self, len: u64) -> DBResult<()> {
+        self.file.set_len(len).map_err(from_io_error)?;
+        self.file.sync().map_err(from_io_error)?;
+        self.offset.store(len, Ordering::Release);
+        Ok(())
+    }
+
+    /// Read until `buf` is full or EOF is reached, returns the bytes read.
+    fn read_full_at(&self, buf: &mut [u8], offset: u64) -> DBResult<usize> {
+        let mut nread = 0;
+        while nread < buf.len() {
+            match self.file.read_at(&mut buf[nread..], offset + nread as u64) {
+                Ok(0) => break, // EOF
+                Ok(bytes) => nread += bytes,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(from_io_error(e)),
+            }
+        }
+        Ok(nread)
+    }
+}
+
+/// Whether `data` starts with a record whose checksum matches.
+fn starts_with_record(data: &[u8]) -> bool {
+    if data.len() < ENTRY_HEADER_SIZE {
+        return false;
+    }
+    let length = decode_entry_length(data);
+    if length > data.len() as u64 {
+        return false;
+    }
+    let bytes = &data[..length as usize];
+    matches!(BatchHeader::decode(bytes, true), Ok(Some(_)))
+        || OwnedEntry::decode_from_bytes(bytes, true).is_ok()
 }
 
 #[cfg(test)]
 mod tests {
+    use std::io;
+    use std::path::Path;
+
+    use crate::env::{Env, EnvFile, MemEnv};
+    use crate::errors::{DBError, ErrorKind};
+    use crate::filename::FileType;
     use crate::model::{OpType, OwnedEntry};
 
-    use super::LogFile;
+    use super::{LogFile, LogRecord};
+
+    fn create_log_file(id: u64) -> LogFile {
+        let env = MemEnv::new();
+        env.create_dir_all(Path::new("/db")).unwrap();
+        let path = FileType::Log.get_full_filepath("/db".into(), id);
+        LogFile::create(&env, id, path).unwrap()
+    }
 
     #[test]
     fn test_write_read() {
-        let f = std::fs::File::options()
-            .create(true)
-            .write(true)
-            .truncate(true)
-            .read(true)
-            .open("/tmp/00000000001")
-            .unwrap();
-        let mut dbf = LogFile::new(1, f);
+        let dbf = create_log_file(1);
         let mut oe = OwnedEntry {
             op_type: crate::model::OpType::Put,
             key: Vec::from("name"),
             value: Some(Vec::from("guoxiang")),
             ts: Some(100000000000003),
+            seq: 1,
+            expire_at: 0,
         };
         let data = oe.as_ref_entry().encode_to_bytes();
         let handle = dbf.write_entry(&oe).unwrap();
@@ -120,7 +346,7 @@ mod tests {
         assert!(handle.length == data.len() as u64);
         println!("{:?}", handle);
 
-        let read_entry = dbf.read_entry(handle.clone(), false).unwrap();
+        let read_entry = dbf.read_entry(handle, false).unwrap();
         assert_eq!(read_entry, oe);
         println!("{:?}", read_entry);
 
@@ -130,6 +356,7 @@ mod tests {
         oe.key = Vec::from("name");
         oe.value = None;
         oe.ts = Some(100000000000004);
+        oe.seq = 2;
 
         let data = oe.as_ref_entry().encode_to_bytes();
         let handle = dbf.write_entry(&oe).unwrap();
@@ -138,8 +365,114 @@ mod tests {
         assert!(handle.length == data.len() as u64);
         assert!(handle.offset == last_offset);
         println!("{:?}", handle);
-        let read_entry = dbf.read_entry(handle.clone(), false).unwrap();
+        let read_entry = dbf.read_entry(handle, false).unwrap();
         assert_eq!(read_entry, oe);
         println!("{:?}", read_entry);
+
+        // scan from the beginning
+        let (first, h1) = match dbf.read_entry_at(0, false).unwrap() {
+            LogRecord::Entry(e, h) => (e, h),
+            r => panic!("unexpected {:?}", r),
+        };
+        assert_eq!(first.op_type, OpType::Put);
+        assert_eq!(h1.offset, 0);
+        let (second, h2) = match dbf.read_entry_at(h1.length, false).unwrap() {
+            LogRecord::Entry(e, h) => (e, h),
+            r => panic!("unexpected {:?}", r),
+        };
+        assert_eq!(second, oe);
+        assert_eq!(h2.offset, last_offset);
+        assert!(matches!(
+            dbf.read_entry_at(h2.offset + h2.length, false).unwrap(),
+            LogRecord::Eof
+        ));
+
+        // a half written record
+        let end = dbf.get_offset();
+        dbf.write_entry(&oe).unwrap();
+        dbf.truncate(end + 3).unwrap();
+        assert!(matches!(
+            dbf.read_entry_at(end, false).unwrap(),
+            LogRecord::Incomplete
+        ));
+        assert!(dbf.is_last_record(end).unwrap());
+        // not so if a record follows
+        assert!(!dbf.is_last_record(h2.offset - 1).unwrap());
+        dbf.truncate(end).unwrap();
+        assert!(matches!(
+            dbf.read_entry_at(end, false).unwrap(),
+            LogRecord::Eof
+        ));
+    }
+
+    #[test]
+    fn test_verify_checksum() {
+        let dbf = create_log_file(2);
+        let oe = OwnedEntry {
+            op_type: OpType::Put,
+            key: Vec::from("name"),
+            value: Some(Vec::from("guoxiang")),
+            ts: Some(100000000000005),
+            ..Default::default()
+        };
+        let handle = dbf.write_entry(&oe).unwrap();
+        assert_eq!(dbf.read_entry(handle, true).unwrap(), oe);
+
+        // flip one byte of the value
+        dbf.file
+            .write_at(b"G", handle.offset + handle.length - 8)
+            .unwrap();
+        assert!(matches!(
+            dbf.read_entry(handle, true),
+            Err(DBError::Corruption {
+                file_id: Some(2),
+                offset: Some(0),
+                ..
+            })
+        ));
+        assert_eq!(
+            dbf.read_entry(handle, false).unwrap().value,
+            Some(Vec::from("Guoxiang"))
+        );
+        assert!(matches!(
+            dbf.read_entry_at(0, true).unwrap(),
+            LogRecord::Corrupted { .. }
+        ));
+    }
+
+    /// A file which takes no more bytes.
+    struct FullFile;
+
+    impl EnvFile for FullFile {
+        fn read_at(&self, _: &mut [u8], _: u64) -> io::Result<usize> {
+            Ok(0)
+        }
+
+        fn write_at(&self, _: &[u8], _: u64) -> io::Result<usize> {
+            Ok(0)
+        }
+
+        fn sync(&self) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn set_len(&self, _: u64) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn size(&self) -> io::Result<u64> {
+            Ok(0)
+        }
+    }
+
+    #[test]
+    fn test_append_write_zero() {
+        let dbf = LogFile::new(3, Box::new(FullFile));
+        let e = dbf.append(b"record").unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::Io);
+        assert!(matches!(
+            e,
+            DBError::Io { ref source, .. } if source.kind() == io::ErrorKind::WriteZero
+        ));
     }
 }
@@ -0,0 +1,441 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::os::unix::prelude::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+
+/// How `Env::open` opens a file, it is always readable and writable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpenMode {
+    /// The file must exist.
+    Existing,
+    /// Create the file if it is missing, keep its content otherwise.
+    Create,
+    /// Create the file if it is missing, empty it otherwise.
+    Truncate,
+    /// Create the file, fail if it exists.
+    CreateNew,
+}
+
+/// A file opened by an `Env`, reads and writes are at a given offset.
+pub trait EnvFile: Send + Sync {
+    /// Read into `buf` at `offset`, returns the bytes read which may be
+    /// less than asked for, 0 at the end of file.
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize>;
+    /// Write `buf` at `offset`, returns the bytes written which may be
+    /// less than asked for.
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<usize>;
+    /// Persist the content of the file.
+    fn sync(&self) -> io::Result<()>;
+    fn set_len(&self, len: u64) -> io::Result<()>;
+    fn size(&self) -> io::Result<u64>;
+}
+
+/// Returned by `Env::lock`, the lock is held until it is dropped.
+pub trait FileLock: Send + Sync {}
+
+/// The file system the db works on, every file and directory goes
+/// through the `Env` of `Options`.
+pub trait Env: Send + Sync + fmt::Debug {
+    fn open(&self, path: &Path, mode: OpenMode) -> io::Result<Box<dyn EnvFile>>;
+    /// Replace `to` with `from` at once.
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+    /// The names of the entries of the directory `dir`.
+    fn list(&self, dir: &Path) -> io::Result<Vec<String>>;
+    fn remove(&self, path: &Path) -> io::Result<()>;
+    fn exists(&self, path: &Path) -> bool;
+    fn create_dir_all(&self, path: &Path) -> io::Result<()>;
+    /// Persist the entries of the directory, e.g. the result of a rename.
+    fn sync_dir(&self, path: &Path) -> io::Result<()>;
+    /// Take the exclusive lock of the file `path`, which is created if
+    /// missing. Fails with `io::ErrorKind::WouldBlock` if it is held.
+    fn lock(&self, path: &Path) -> io::Result<Box<dyn FileLock>>;
+}
+
+/// Read the whole file `path`.
+pub(crate) fn read_file(env: &dyn Env, path: &Path) -> io::Result<Vec<u8>> {
+    let file = env.open(path, OpenMode::Existing)?;
+    let mut data = vec![0; file.size()? as usize];
+    let mut nread = 0;
+    while nread < data.len() {
+        match file.read_at(&mut data[nread..], nread as u64) {
+            Ok(0) => break,
+            Ok(bytes) => nread += bytes,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    data.truncate(nread);
+    Ok(data)
+}
+
+/// Write all of `data` at `offset` of `file`.
+pub(crate) fn write_all_at(file: &dyn EnvFile, data: &[u8], offset: u64) -> io::Result<()> {
+    let mut nwrite = 0;
+    while nwrite < data.len() {
+        match file.write_at(&data[nwrite..], offset + nwrite as u64) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(bytes) => nwrite += bytes,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Replace the file `path` with `data` through the temp file `tmp_path`
+/// which is renamed once synced, so that `path` is always complete. The
+/// directory is not synced.
+pub(crate) fn write_file_atomic(
+    env: &dyn Env,
+    tmp_path: &Path,
+    path: &Path,
+    data: &[u8],
+) -> io::Result<()> {
+    let write_tmp = || -> io::Result<()> {
+        let file = env.open(tmp_path, OpenMode::Truncate)?;
+        write_all_at(file.as_ref(), data, 0)?;
+        file.sync()?;
+        env.rename(tmp_path, path)
+    };
+    if let Err(e) = write_tmp() {
+        let _ = env.remove(tmp_path);
+        return Err(e);
+    }
+    Ok(())
+}
+
+/// The `Env` of the local file system.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PosixEnv;
+
+impl EnvFile for File {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        FileExt::read_at(self, buf, offset)
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<usize> {
+        FileExt::write_at(self, buf, offset)
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        self.sync_all()
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        File::set_len(self, len)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+}
+
+/// Holds an advisory lock on the file, which is released when the file
+/// is closed.
+struct PosixLock {
+    _file: File,
+}
+
+impl FileLock for PosixLock {}
+
+impl Env for PosixEnv {
+    fn open(&self, path: &Path, mode: OpenMode) -> io::Result<Box<dyn EnvFile>> {
+        let mut options = File::options();
+        options.read(true).write(true);
+        match mode {
+            OpenMode::Existing => {}
+            OpenMode::Create => {
+                options.create(true);
+            }
+            OpenMode::Truncate => {
+                options.create(true).truncate(true);
+            }
+            OpenMode::CreateNew => {
+                options.create_new(true);
+            }
+        }
+        Ok(Box::new(options.open(path)?))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        std::fs::rename(from, to)
+    }
+
+    fn list(&self, dir: &Path) -> io::Result<Vec<String>> {
+        let mut names = vec![];
+        for dirent in std::fs::read_dir(dir)? {
+            if let Ok(name) = dirent?.file_name().into_string() {
+                names.push(name);
+            }
+        }
+        Ok(names)
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        std::fs::remove_file(path)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        path.exists()
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        std::fs::create_dir_all(path)
+    }
+
+    fn sync_dir(&self, path: &Path) -> io::Result<()> {
+        File::open(path)?.sync_all()
+    }
+
+    fn lock(&self, path: &Path) -> io::Result<Box<dyn FileLock>> {
+        let file = File::options()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path)?;
+        match file.try_lock() {
+            Ok(()) => Ok(Box::new(PosixLock { _file: file })),
+            Err(std::fs::TryLockError::WouldBlock) => Err(io::ErrorKind::WouldBlock.into()),
+            Err(std::fs::TryLockError::Error(e)) => Err(e),
+        }
+    }
+}
+
+/// An `Env` keeping its files in memory, they live as long as any clone
+/// of the env. Tests use one env per db so they run in parallel and
+/// leave nothing behind.
+#[derive(Debug, Clone, Default)]
+pub struct MemEnv {
+    fs: Arc<Mutex<MemFs>>,
+}
+
+type MemData = Arc<RwLock<Vec<u8>>>;
+
+#[derive(Debug, Default)]
+struct MemFs {
+    files: BTreeMap<PathBuf, MemData>,
+    dirs: BTreeSet<PathBuf>,
+    locks: BTreeSet<PathBuf>,
+}
+
+impl MemFs {
+    fn check_parent(&self, path: &Path) -> io::Result<()> {
+        match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() && !self.dirs.contains(dir) => {
+                Err(io::ErrorKind::NotFound.into())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// A file of a `MemEnv`, it keeps its data when removed like an open
+/// file does.
+struct MemFile(MemData);
+
+impl EnvFile for MemFile {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let data = self.0.read().unwrap();
+        let start = (offset as usize).min(data.len());
+        let n = buf.len().min(data.len() - start);
+        buf[..n].copy_from_slice(&data[start..start + n]);
+        Ok(n)
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<usize> {
+        let mut data = self.0.write().unwrap();
+        let end = offset as usize + buf.len();
+        if data.len() < end {
+            data.resize(end, 0);
+        }
+        data[offset as usize..end].copy_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        self.0.write().unwrap().resize(len as usize, 0);
+        Ok(())
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.0.read().unwrap().len() as u64)
+    }
+}
+
+struct MemLock {
+    fs: Arc<Mutex<MemFs>>,
+    path: PathBuf,
+}
+
+impl FileLock for MemLock {}
+
+impl Drop for MemLock {
+    fn drop(&mut self) {
+        self.fs.lock().unwrap().locks.remove(&self.path);
+    }
+}
+
+impl MemEnv {
+    pub fn new() -> MemEnv {
+        MemEnv::default()
+    }
+}
+
+impl Env for MemEnv {
+    fn open(&self, path: &Path, mode: OpenMode) -> io::Result<Box<dyn EnvFile>> {
+        let mut fs = self.fs.lock().unwrap();
+        fs.check_parent(path)?;
+        let data = match (fs.files.get(path), mode) {
+            (Some(_), OpenMode::CreateNew) => return Err(io::ErrorKind::AlreadyExists.into()),
+            (Some(data), OpenMode::Truncate) => {
+                data.write().unwrap().clear();
+                data.clone()
+            }
+            (Some(data), _) => data.clone(),
+            (None, OpenMode::Existing) => return Err(io::ErrorKind::NotFound.into()),
+            (None, _) => fs.files.entry(path.to_path_buf()).or_default().clone(),
+        };
+        Ok(Box::new(MemFile(data)))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut fs = self.fs.lock().unwrap();
+        fs.check_parent(to)?;
+        let data = fs.files.remove(from).ok_or(io::ErrorKind::NotFound)?;
+        fs.files.insert(to.to_path_buf(), data);
+        Ok(())
+    }
+
+    fn list(&self, dir: &Path) -> io::Result<Vec<String>> {
+        let fs = self.fs.lock().unwrap();
+        if !fs.dirs.contains(dir) {
+            return Err(io::ErrorKind::NotFound.into());
+        }
+        let children = fs.files.keys().chain(fs.dirs.iter());
+        Ok(children
+            .filter(|x| x.parent() == Some(dir))
+            .filter_map(|x| x.file_name()?.to_str().map(str::to_owned))
+            .collect())
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        match self.fs.lock().unwrap().files.remove(path) {
+            Some(_) => Ok(()),
+            None => Err(io::ErrorKind::NotFound.into()),
+        }
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        let fs = self.fs.lock().unwrap();
+        fs.files.contains_key(path) || fs.dirs.contains(path)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        let mut fs = self.fs.lock().unwrap();
+        for dir in path.ancestors().filter(|x| !x.as_os_str().is_empty()) {
+            fs.dirs.insert(dir.to_path_buf());
+        }
+        Ok(())
+    }
+
+    fn sync_dir(&self, path: &Path) -> io::Result<()> {
+        match self.fs.lock().unwrap().dirs.contains(path) {
+            true => Ok(()),
+            false => Err(io::ErrorKind::NotFound.into()),
+        }
+    }
+
+    fn lock(&self, path: &Path) -> io::Result<Box<dyn FileLock>> {
+        drop(self.open(path, OpenMode::Create)?);
+        let mut fs = self.fs.lock().unwrap();
+        if !fs.locks.insert(path.to_path_buf()) {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        Ok(Box::new(MemLock {
+            fs: self.fs.clone(),
+            path: path.to_path_buf(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::path::Path;
+
+    use super::{read_file, write_file_atomic, Env, MemEnv, OpenMode, PosixEnv};
+
+    /// The behavior both envs share.
+    fn check_env(env: &dyn Env, dir: &Path) {
+        env.create_dir_all(dir).unwrap();
+        let path = dir.join("a");
+        let err = env.open(&path, OpenMode::Existing).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        let file = env.open(&path, OpenMode::CreateNew).unwrap();
+        assert_eq!(file.write_at(b"hello", 0).unwrap(), 5);
+        assert_eq!(file.write_at(b"!", 7).unwrap(), 1);
+        assert_eq!(file.size().unwrap(), 8);
+        let mut buf = [0; 16];
+        assert_eq!(file.read_at(&mut buf, 3).unwrap(), 5);
+        assert_eq!(&buf[..5], b"lo\0\0!");
+        file.set_len(5).unwrap();
+        assert_eq!(read_file(env, &path).unwrap(), b"hello");
+        let err = env.open(&path, OpenMode::CreateNew).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(
+            env.open(&path, OpenMode::Create).unwrap().size().unwrap(),
+            5
+        );
+
+        write_file_atomic(env, &dir.join("tmp"), &dir.join("b"), b"data").unwrap();
+        assert_eq!(read_file(env, &dir.join("b")).unwrap(), b"data");
+        let mut names = env.list(dir).unwrap();
+        names.sort();
+        assert_eq!(names, ["a", "b"]);
+        env.rename(&dir.join("b"), &path).unwrap();
+        assert!(!env.exists(&dir.join("b")));
+        assert_eq!(read_file(env, &path).unwrap(), b"data");
+        assert_eq!(
+            env.open(&path, OpenMode::Truncate).unwrap().size().unwrap(),
+            0
+        );
+        env.remove(&path).unwrap();
+        assert!(!env.exists(&path));
+        assert!(env.remove(&path).is_err());
+
+        let lock = env.lock(&dir.join("LOCK")).unwrap();
+        let err = env.lock(&dir.join("LOCK")).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        drop(lock);
+        env.lock(&dir.join("LOCK")).unwrap();
+        env.sync_dir(dir).unwrap();
+    }
+
+    #[test]
+    fn test_posix_env() {
+        let dir = std::env::temp_dir().join("bitcask_test_posix_env");
+        let _ = std::fs::remove_dir_all(&dir);
+        check_env(&PosixEnv, &dir);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_mem_env() {
+        let env = MemEnv::new();
+        check_env(&env, Path::new("/db"));
+        // an open file keeps its data when removed
+        let file = env.open(Path::new("/db/c"), OpenMode::Create).unwrap();
+        file.write_at(b"c", 0).unwrap();
+        env.remove(Path::new("/db/c")).unwrap();
+        assert_eq!(file.size().unwrap(), 1);
+        assert!(env.open(Path::new("/nodir/c"), OpenMode::Create).is_err());
+        assert!(env.list(Path::new("/nodir")).is_err());
+    }
+}
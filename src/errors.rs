@@ -1,7 +1,240 @@
+use std::fmt;
+use std::sync::Arc;
+
+/// A coarse and stable classification of `DBError`, callers use it to
+/// decide how to react, e.g. whether an operation is worth retrying.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ErrorKind {
+    Io,
+    Corruption,
+    NotFound,
+    AlreadyExists,
+    InvalidArgument,
+    Locked,
+    Background,
+    Internal,
+}
+
 #[derive(Debug)]
-pub struct DBError {}
+pub enum DBError {
+    Io {
+        /// what was being done, may be empty.
+        context: String,
+        source: std::io::Error,
+    },
+    Corruption {
+        msg: String,
+        /// the file and offset of the broken data, when known.
+        file_id: Option<u64>,
+        offset: Option<u64>,
+    },
+    /// The db does not exist and `create_if_missing` is off.
+    NotFound(String),
+    /// The db exists and `error_if_exists` is on.
+    AlreadyExists(String),
+    InvalidArgument(String),
+    /// The db is opened by another `BitcaskDB`, in this or another process.
+    Locked(String),
+    /// A previous failure left the db in a state which refuses writes.
+    Background(Arc<DBError>),
+    /// A broken invariant of the db itself, the data on disk is not
+    /// known to be damaged.
+    Internal(String),
+}
+
 pub type DBResult<T> = std::result::Result<T, DBError>;
 
+impl DBError {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            DBError::Io { .. } => ErrorKind::Io,
+            DBError::Corruption { .. } => ErrorKind::Corruption,
+            DBError::NotFound(_) => ErrorKind::NotFound,
+            DBError::AlreadyExists(_) => ErrorKind::AlreadyExists,
+            DBError::InvalidArgument(_) => ErrorKind::InvalidArgument,
+            DBError::Locked(_) => ErrorKind::Locked,
+            DBError::Background(_) => ErrorKind::Background,
+            DBError::Internal(_) => ErrorKind::Internal,
+        }
+    }
+
+    /// A copy of the error for another caller failed by the same cause,
+    /// an io error keeps its kind and os code but not its inner source.
+    pub(crate) fn duplicate(&self) -> DBError {
+        match self {
+            DBError::Io { context, source } => DBError::Io {
+                context: context.clone(),
+                source: match source.raw_os_error() {
+                    Some(code) => std::io::Error::from_raw_os_error(code),
+                    None => std::io::Error::new(source.kind(), source.to_string()),
+                },
+            },
+            DBError::Corruption {
+                msg,
+                file_id,
+                offset,
+            } => DBError::Corruption {
+                msg: msg.clone(),
+                file_id: *file_id,
+                offset: *offset,
+            },
+            DBError::NotFound(x) => DBError::NotFound(x.clone()),
+            DBError::AlreadyExists(x) => DBError::AlreadyExists(x.clone()),
+            DBError::InvalidArgument(x) => DBError::InvalidArgument(x.clone()),
+            DBError::Locked(x) => DBError::Locked(x.clone()),
+            DBError::Background(e) => DBError::Background(e.clone()),
+            DBError::Internal(x) => DBError::Internal(x.clone()),
+        }
+    }
+
+    /// Attach the location to a corruption error which has none yet.
+    pub(crate) fn at(self, file_id: u64, offset: u64) -> DBError {
+        match self {
+            DBError::Corruption {
+                msg,
+                file_id: None,
+                offset: None,
+            } => DBError::Corruption {
+                msg,
+                file_id: Some(file_id),
+                offset: Some(offset),
+            },
+            e => e,
+        }
+    }
+}
+
+impl fmt::Display for DBError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DBError::Io { context, source } if context.is_empty() => {
+                write!(f, "IO error: {}", source)
+            }
+            DBError::Io { context, source } => write!(f, "IO error: {}: {}", context, source),
+            DBError::Corruption {
+                msg,
+                file_id,
+                offset,
+            } => {
+                write!(f, "Corruption: {}", msg)?;
+                if let Some(file_id) = file_id {
+                    write!(f, " in file {}", file_id)?;
+                }
+                if let Some(offset) = offset {
+                    write!(f, " at offset {}", offset)?;
+                }
+                Ok(())
+            }
+            DBError::NotFound(path) => write!(f, "Not found: {} does not exist", path),
+            DBError::AlreadyExists(path) => write!(f, "Already exists: {}", path),
+            DBError::InvalidArgument(msg) => write!(f, "Invalid argument: {}", msg),
+            DBError::Locked(path) => write!(f, "Locked: {} is held by another db", path),
+            DBError::Background(e) => write!(f, "Background error: {}", e),
+            DBError::Internal(msg) => write!(f, "Internal error: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for DBError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DBError::Io { source, .. } => Some(source),
+            DBError::Background(e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
+
 pub(crate) fn from_io_error(e: std::io::Error) -> DBError {
-    DBError {}
+    DBError::Io {
+        context: String::new(),
+        source: e,
+    }
+}
+
+pub(crate) fn io_error(context: String, e: std::io::Error) -> DBError {
+    DBError::Io { context, source: e }
+}
+
+pub(crate) fn corruption(msg: &str) -> DBError {
+    DBError::Corruption {
+        msg: msg.to_owned(),
+        file_id: None,
+        offset: None,
+    }
+}
+
+pub(crate) fn corruption_at(msg: &str, file_id: u64, offset: u64) -> DBError {
+    corruption(msg).at(file_id, offset)
+}
+
+pub(crate) fn invalid_argument(msg: &str) -> DBError {
+    DBError::InvalidArgument(msg.to_owned())
+}
+
+pub(crate) fn internal(msg: &str) -> DBError {
+    DBError::Internal(msg.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+    use std::sync::Arc;
+
+    use super::{corruption, corruption_at, from_io_error, internal, io_error, DBError, ErrorKind};
+
+    #[test]
+    fn test_kind_and_display() {
+        let e = io_error(
+            "open 000000001.dat".to_owned(),
+            std::io::Error::other("disk on fire"),
+        );
+        assert_eq!(e.kind(), ErrorKind::Io);
+        assert_eq!(e.to_string(), "IO error: open 000000001.dat: disk on fire");
+        assert!(e.source().is_some());
+        let e = from_io_error(std::io::Error::other("disk on fire"));
+        assert_eq!(e.to_string(), "IO error: disk on fire");
+
+        let e = corruption_at("entry checksum mismatch", 3, 128);
+        assert_eq!(e.kind(), ErrorKind::Corruption);
+        assert_eq!(
+            e.to_string(),
+            "Corruption: entry checksum mismatch in file 3 at offset 128"
+        );
+        // the first location wins
+        let e = e.at(4, 0);
+        assert!(matches!(
+            e,
+            DBError::Corruption {
+                file_id: Some(3),
+                offset: Some(128),
+                ..
+            }
+        ));
+        assert_eq!(corruption("bad").to_string(), "Corruption: bad");
+
+        let e = DBError::Background(Arc::new(corruption("bad")));
+        assert_eq!(e.kind(), ErrorKind::Background);
+        assert_eq!(e.to_string(), "Background error: Corruption: bad");
+        assert!(e.source().is_some());
+        assert_eq!(DBError::Locked("LOCK".into()).kind(), ErrorKind::Locked);
+        let e = internal("broken invariant");
+        assert_eq!(e.kind(), ErrorKind::Internal);
+        assert_eq!(e.to_string(), "Internal error: broken invariant");
+    }
+
+    #[test]
+    fn test_duplicate() {
+        let e = io_error(
+            "write 000000001.dat".to_owned(),
+            std::io::Error::from_raw_os_error(28),
+        );
+        let copy = e.duplicate();
+        assert_eq!(copy.kind(), ErrorKind::Io);
+        assert_eq!(copy.to_string(), e.to_string());
+        let e = from_io_error(std::io::Error::other("disk on fire"));
+        assert_eq!(e.duplicate().to_string(), e.to_string());
+        let e = corruption_at("bad", 1, 2);
+        assert_eq!(e.duplicate().to_string(), e.to_string());
+    }
 }
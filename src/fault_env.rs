@@ -0,0 +1,388 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::env::{read_file, write_all_at, Env, EnvFile, FileLock, OpenMode};
+
+/// The operations `FaultEnv` can fail, `Sync` covers `Env::sync_dir` as
+/// well. `EnvFile::set_len` and truncating a file on open are writes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum FaultOp {
+    Read,
+    Write,
+    Sync,
+    Rename,
+}
+
+/// An `Env` over another one which fails chosen operations and cuts the
+/// power: every file loses what it got since its last sync, and the files
+/// created, renamed or removed since the last sync of their directory are
+/// put back as they were.
+#[derive(Clone)]
+pub(crate) struct FaultEnv {
+    base: Arc<dyn Env>,
+    state: Arc<Mutex<FaultState>>,
+}
+
+#[derive(Default)]
+struct FaultState {
+    /// the synced length of each file, shared with its open handles so
+    /// that it follows the file through a rename.
+    synced: HashMap<PathBuf, Arc<AtomicU64>>,
+    /// the directory changes not synced yet, in order.
+    dir_ops: Vec<DirOp>,
+    counts: HashMap<FaultOp, u64>,
+    /// the count at which an operation fails.
+    faults: HashMap<FaultOp, u64>,
+    powered_off: bool,
+}
+
+enum DirOp {
+    Create(PathBuf),
+    /// `from` was renamed over `to`, which had `replaced` if it existed.
+    Rename {
+        from: PathBuf,
+        to: PathBuf,
+        replaced: Option<(Vec<u8>, Arc<AtomicU64>)>,
+    },
+    Remove {
+        path: PathBuf,
+        data: Vec<u8>,
+        synced: Arc<AtomicU64>,
+    },
+}
+
+impl DirOp {
+    fn dir(&self) -> Option<&Path> {
+        match self {
+            DirOp::Create(path) | DirOp::Remove { path, .. } => path.parent(),
+            DirOp::Rename { to, .. } => to.parent(),
+        }
+    }
+}
+
+fn power_off_error() -> io::Error {
+    io::Error::other("the power is off")
+}
+
+impl FaultState {
+    /// Count `op` and fail it if it is the chosen one or the power is off.
+    fn inject(&mut self, op: FaultOp) -> io::Result<()> {
+        if self.powered_off {
+            return Err(power_off_error());
+        }
+        let count = self.counts.entry(op).or_default();
+        *count += 1;
+        if self.faults.get(&op) == Some(count) {
+            self.faults.remove(&op);
+            // EIO
+            return Err(io::Error::from_raw_os_error(5));
+        }
+        Ok(())
+    }
+
+    fn check_power(&self) -> io::Result<()> {
+        match self.powered_off {
+            true => Err(power_off_error()),
+            false => Ok(()),
+        }
+    }
+
+    /// The synced length of `path`, a file this env has not seen yet is
+    /// synced as it is.
+    fn synced_of(&mut self, base: &dyn Env, path: &Path) -> Arc<AtomicU64> {
+        self.synced
+            .entry(path.to_path_buf())
+            .or_insert_with(|| {
+                let size = base.open(path, OpenMode::Existing).and_then(|x| x.size());
+                Arc::new(AtomicU64::new(size.unwrap_or(0)))
+            })
+            .clone()
+    }
+}
+
+fn restore_file(base: &dyn Env, path: &Path, data: &[u8]) {
+    let file = base.open(path, OpenMode::Truncate).unwrap();
+    write_all_at(file.as_ref(), data, 0).unwrap();
+}
+
+impl FaultEnv {
+    pub(crate) fn new(base: Arc<dyn Env>) -> FaultEnv {
+        FaultEnv {
+            base,
+            state: Arc::new(Mutex::new(FaultState::default())),
+        }
+    }
+
+    /// Fail the `n`-th `op` from now on, counting from 1.
+    pub(crate) fn fail_nth(&self, op: FaultOp, n: u64) {
+        let mut state = self.state.lock().unwrap();
+        let count = state.counts.get(&op).copied().unwrap_or(0);
+        state.faults.insert(op, count + n);
+    }
+
+    /// The number of times `op` was done, failed ones included.
+    pub(crate) fn count(&self, op: FaultOp) -> u64 {
+        let state = self.state.lock().unwrap();
+        state.counts.get(&op).copied().unwrap_or(0)
+    }
+
+    /// Drop everything which is not synced, and fail every operation
+    /// changing a file until `power_on`.
+    pub(crate) fn power_cut(&self) {
+        let base = self.base.as_ref();
+        let mut state = self.state.lock().unwrap();
+        while let Some(op) = state.dir_ops.pop() {
+            match op {
+                DirOp::Create(path) => {
+                    let _ = base.remove(&path);
+                    state.synced.remove(&path);
+                }
+                DirOp::Rename { from, to, replaced } => {
+                    if base.rename(&to, &from).is_ok() {
+                        if let Some(synced) = state.synced.remove(&to) {
+                            state.synced.insert(from, synced);
+                        }
+                    }
+                    if let Some((data, synced)) = replaced {
+                        restore_file(base, &to, &data);
+                        state.synced.insert(to, synced);
+                    }
+                }
+                DirOp::Remove { path, data, synced } => {
+                    restore_file(base, &path, &data);
+                    state.synced.insert(path, synced);
+                }
+            }
+        }
+        for (path, synced) in &state.synced {
+            if let Ok(file) = base.open(path, OpenMode::Existing) {
+                let len = file.size().unwrap().min(synced.load(Ordering::SeqCst));
+                file.set_len(len).unwrap();
+            }
+        }
+        state.powered_off = true;
+    }
+
+    /// Boot again after `power_cut`, the pending faults are cleared.
+    pub(crate) fn power_on(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.powered_off = false;
+        state.faults.clear();
+    }
+}
+
+impl fmt::Debug for FaultEnv {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FaultEnv")
+            .field("base", &self.base)
+            .finish()
+    }
+}
+
+struct FaultFile {
+    file: Box<dyn EnvFile>,
+    synced: Arc<AtomicU64>,
+    state: Arc<Mutex<FaultState>>,
+}
+
+impl EnvFile for FaultFile {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        self.state.lock().unwrap().inject(FaultOp::Read)?;
+        self.file.read_at(buf, offset)
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        let powered_off = state.powered_off;
+        if let Err(e) = state.inject(FaultOp::Write) {
+            if !powered_off {
+                // a failed write may leave a part of it behind
+                let _ = self.file.write_at(&buf[..buf.len() / 2], offset);
+            }
+            return Err(e);
+        }
+        self.file.write_at(buf, offset)
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.inject(FaultOp::Sync)?;
+        self.file.sync()?;
+        self.synced.store(self.file.size()?, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.inject(FaultOp::Write)?;
+        self.file.set_len(len)?;
+        self.synced.fetch_min(len, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        self.file.size()
+    }
+}
+
+impl Env for FaultEnv {
+    fn open(&self, path: &Path, mode: OpenMode) -> io::Result<Box<dyn EnvFile>> {
+        let base = self.base.as_ref();
+        let mut state = self.state.lock().unwrap();
+        let exists = base.exists(path);
+        match mode {
+            OpenMode::Existing => {}
+            OpenMode::Truncate if exists => state.inject(FaultOp::Write)?,
+            _ if exists => {}
+            _ => state.check_power()?,
+        }
+        let file = base.open(path, mode)?;
+        let synced = state.synced_of(base, path);
+        if !exists {
+            synced.store(0, Ordering::SeqCst);
+            state.dir_ops.push(DirOp::Create(path.to_path_buf()));
+        } else if mode == OpenMode::Truncate {
+            synced.store(0, Ordering::SeqCst);
+        }
+        Ok(Box::new(FaultFile {
+            file,
+            synced,
+            state: self.state.clone(),
+        }))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let base = self.base.as_ref();
+        let mut state = self.state.lock().unwrap();
+        state.inject(FaultOp::Rename)?;
+        let replaced = match read_file(base, to) {
+            Ok(data) => Some((data, state.synced_of(base, to))),
+            Err(_) => None,
+        };
+        let synced = state.synced_of(base, from);
+        base.rename(from, to)?;
+        state.synced.remove(from);
+        state.synced.insert(to.to_path_buf(), synced);
+        state.dir_ops.push(DirOp::Rename {
+            from: from.to_path_buf(),
+            to: to.to_path_buf(),
+            replaced,
+        });
+        Ok(())
+    }
+
+    fn list(&self, dir: &Path) -> io::Result<Vec<String>> {
+        self.base.list(dir)
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        let base = self.base.as_ref();
+        let mut state = self.state.lock().unwrap();
+        state.check_power()?;
+        let data = read_file(base, path)?;
+        let synced = state.synced_of(base, path);
+        base.remove(path)?;
+        state.synced.remove(path);
+        state.dir_ops.push(DirOp::Remove {
+            path: path.to_path_buf(),
+            data,
+            synced,
+        });
+        Ok(())
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.base.exists(path)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        self.state.lock().unwrap().check_power()?;
+        self.base.create_dir_all(path)
+    }
+
+    fn sync_dir(&self, path: &Path) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.inject(FaultOp::Sync)?;
+        self.base.sync_dir(path)?;
+        state.dir_ops.retain(|x| x.dir() != Some(path));
+        Ok(())
+    }
+
+    fn lock(&self, path: &Path) -> io::Result<Box<dyn FileLock>> {
+        self.base.lock(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::sync::Arc;
+
+    use super::{FaultEnv, FaultOp};
+    use crate::env::{read_file, write_file_atomic};
+    use crate::{Env, MemEnv, OpenMode};
+
+    #[test]
+    fn test_power_cut() {
+        let env = FaultEnv::new(Arc::new(MemEnv::new()));
+        let dir = Path::new("/db");
+        env.create_dir_all(dir).unwrap();
+        let (a, b) = (dir.join("a"), dir.join("b"));
+        let file = env.open(&a, OpenMode::CreateNew).unwrap();
+        file.write_at(b"synced", 0).unwrap();
+        file.sync().unwrap();
+        env.sync_dir(dir).unwrap();
+        file.write_at(b" not synced", 6).unwrap();
+        write_file_atomic(&env, &dir.join("tmp"), &a, b"new").unwrap();
+        let file = env.open(&b, OpenMode::CreateNew).unwrap();
+        file.write_at(b"b", 0).unwrap();
+        file.sync().unwrap();
+
+        env.power_cut();
+        assert!(file.write_at(b"b", 1).is_err());
+        assert!(env.remove(&a).is_err());
+        env.power_on();
+        // the rename over a and the creation of b are not synced
+        assert_eq!(read_file(&env, &a).unwrap(), b"synced");
+        assert!(!env.exists(&b));
+        assert!(!env.exists(&dir.join("tmp")));
+
+        write_file_atomic(&env, &dir.join("tmp"), &a, b"new").unwrap();
+        env.sync_dir(dir).unwrap();
+        env.remove(&a).unwrap();
+        env.power_cut();
+        env.power_on();
+        assert_eq!(read_file(&env, &a).unwrap(), b"new");
+    }
+
+    #[test]
+    fn test_fail_nth() {
+        let env = FaultEnv::new(Arc::new(MemEnv::new()));
+        let dir = Path::new("/db");
+        env.create_dir_all(dir).unwrap();
+        let file = env.open(&dir.join("a"), OpenMode::CreateNew).unwrap();
+        env.sync_dir(dir).unwrap();
+        env.fail_nth(FaultOp::Write, 2);
+        env.fail_nth(FaultOp::Sync, 1);
+        file.write_at(b"1234", 0).unwrap();
+        // half of the failed write is there
+        assert!(file.write_at(b"5678", 4).is_err());
+        assert_eq!(file.size().unwrap(), 6);
+        file.write_at(b"5678", 4).unwrap();
+        assert!(file.sync().is_err());
+        file.sync().unwrap();
+        assert_eq!(env.count(FaultOp::Write), 3);
+        assert_eq!(env.count(FaultOp::Sync), 3);
+
+        env.fail_nth(FaultOp::Rename, 1);
+        assert!(env.rename(&dir.join("a"), &dir.join("b")).is_err());
+        env.rename(&dir.join("a"), &dir.join("b")).unwrap();
+        env.power_cut();
+        env.power_on();
+        assert_eq!(read_file(&env, &dir.join("a")).unwrap(), b"12345678");
+    }
+}
@@ -2,6 +2,7 @@ use std::path::PathBuf;
 
 use crate::dbfile::FileId;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub(crate) enum FileType {
     Log,      // log datum file for write
     Rewrite,  // rewrite log datum file caused by compaction
@@ -9,6 +10,7 @@ pub(crate) enum FileType {
     Manifest, // the manifest to manage the whole db and compaction
     Lock,     // lock file
     Current,  // the current file points to the manifest used.
+    Temp,     // temporary file which is renamed to its final name once complete
 }
 
 impl FileType {
@@ -20,6 +22,7 @@ impl FileType {
             FileType::Manifest => format!("MANIFEST-{:09}", file_id).into(),
             FileType::Lock => "LOCK".to_owned().into(),
             FileType::Current => "CURRENT".to_owned().into(),
+            FileType::Temp => format!("{:09}.dbtmp", file_id).into(),
         }
     }
 
@@ -27,6 +30,35 @@ impl FileType {
         let filename = self.get_filename(file_id);
         dbpath.join(&filename).as_path().into()
     }
+
+    /// The reverse of `get_filename`, returns `None` if the filename
+    /// does not belong to the db.
+    pub(crate) fn parse_filename(filename: &str) -> Option<(FileType, FileId)> {
+        match filename {
+            "LOCK" => return Some((FileType::Lock, 0)),
+            "CURRENT" => return Some((FileType::Current, 0)),
+            _ => {}
+        }
+        if let Some(id) = filename.strip_prefix("MANIFEST-") {
+            return parse_file_id(id).map(|id| (FileType::Manifest, id));
+        }
+        let (id, ext) = filename.split_once('.')?;
+        let file_type = match ext {
+            "dat" => FileType::Log,
+            "rew" => FileType::Rewrite,
+            "hit" => FileType::Hint,
+            "dbtmp" => FileType::Temp,
+            _ => return None,
+        };
+        parse_file_id(id).map(|id| (file_type, id))
+    }
+}
+
+fn parse_file_id(s: &str) -> Option<FileId> {
+    if s.is_empty() || !s.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    s.parse().ok()
 }
 
 #[cfg(test)]
@@ -53,4 +85,37 @@ mod tests {
             FileType::Hint.get_filename(3).to_str().unwrap()
         );
     }
+
+    #[test]
+    fn test_parse_filename() {
+        assert_eq!(Some((FileType::Lock, 0)), FileType::parse_filename("LOCK"));
+        assert_eq!(
+            Some((FileType::Current, 0)),
+            FileType::parse_filename("CURRENT")
+        );
+        assert_eq!(
+            Some((FileType::Manifest, 1)),
+            FileType::parse_filename("MANIFEST-000000001")
+        );
+        assert_eq!(
+            Some((FileType::Log, 2)),
+            FileType::parse_filename("000000002.dat")
+        );
+        assert_eq!(
+            Some((FileType::Rewrite, 12)),
+            FileType::parse_filename("000000012.rew")
+        );
+        assert_eq!(
+            Some((FileType::Hint, 3)),
+            FileType::parse_filename("000000003.hit")
+        );
+        assert_eq!(
+            Some((FileType::Temp, 4)),
+            FileType::parse_filename("000000004.dbtmp")
+        );
+        assert_eq!(None, FileType::parse_filename("000000003.txt"));
+        assert_eq!(None, FileType::parse_filename("abc.dat"));
+        assert_eq!(None, FileType::parse_filename("MANIFEST-"));
+        assert_eq!(None, FileType::parse_filename("+1.dat"));
+    }
 }
@@ -0,0 +1,162 @@
+use std::path::PathBuf;
+
+use crate::dbfile::{EntryHandle, FileId};
+use crate::env::{read_file, write_file_atomic, Env};
+use crate::errors::{corruption, from_io_error, DBResult};
+use crate::filename::FileType;
+use crate::model::OpType;
+
+/// seq(8)+ts(8)+expire_at(8)+keysz(4)+offset(8)+length(8)+optype(1)
+const HINT_HEADER_SIZE: usize = 45;
+
+/// The index part of a record in a log file, a hint file holds one for
+/// each live record so that recovery can skip reading the values.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct HintEntry {
+    pub(crate) op_type: OpType,
+    pub(crate) key: Vec<u8>,
+    pub(crate) handle: EntryHandle,
+    pub(crate) seq: u64,
+    /// the write time, see `ValueMeta::timestamp`.
+    pub(crate) ts: u64,
+    pub(crate) expire_at: u64,
+}
+
+/// |seq|ts|expire_at|ksz|offset|length|op|key|...|crc|, the crc at the end
+/// covers all of the records before it.
+fn encode_hint_entries(entries: &[HintEntry]) -> Vec<u8> {
+    let mut data = vec![];
+    for e in entries {
+        data.extend_from_slice(&e.seq.to_be_bytes());
+        data.extend_from_slice(&e.ts.to_be_bytes());
+        data.extend_from_slice(&e.expire_at.to_be_bytes());
+        data.extend_from_slice(&(e.key.len() as u32).to_be_bytes());
+        data.extend_from_slice(&e.handle.offset.to_be_bytes());
+        data.extend_from_slice(&e.handle.length.to_be_bytes());
+        data.push(e.op_type as u8);
+        data.extend_from_slice(&e.key);
+    }
+    let crc = crc32fast::hash(&data);
+    data.extend_from_slice(&crc.to_be_bytes());
+    data
+}
+
+fn decode_hint_entries(file_id: FileId, data: &[u8]) -> DBResult<Vec<HintEntry>> {
+    if data.len() < 4 {
+        return Err(corruption("hint file truncated"));
+    }
+    let (mut data, crc) = data.split_at(data.len() - 4);
+    if crc32fast::hash(data) != u32::from_be_bytes(crc.try_into().unwrap()) {
+        return Err(corruption("hint file checksum mismatch"));
+    }
+
+    let mut entries = vec![];
+    while !data.is_empty() {
+        if data.len() < HINT_HEADER_SIZE {
+            return Err(corruption("hint entry truncated"));
+        }
+        let seq = u64::from_be_bytes(data[0..8].try_into().unwrap());
+        let ts = u64::from_be_bytes(data[8..16].try_into().unwrap());
+        let expire_at = u64::from_be_bytes(data[16..24].try_into().unwrap());
+        let keysz = u32::from_be_bytes(data[24..28].try_into().unwrap()) as usize;
+        let offset = u64::from_be_bytes(data[28..36].try_into().unwrap());
+        let length = u64::from_be_bytes(data[36..44].try_into().unwrap());
+        let op_type = OpType::try_from(data[44])?;
+        if data.len() < HINT_HEADER_SIZE + keysz {
+            return Err(corruption("hint entry truncated"));
+        }
+        entries.push(HintEntry {
+            op_type,
+            key: data[HINT_HEADER_SIZE..HINT_HEADER_SIZE + keysz].to_vec(),
+            handle: EntryHandle {
+                file_id,
+                offset,
+                length,
+            },
+            seq,
+            ts,
+            expire_at,
+        });
+        data = &data[HINT_HEADER_SIZE + keysz..];
+    }
+    Ok(entries)
+}
+
+/// Write the hint file of the log file `file_id`. The content goes to a
+/// temp file which is renamed once synced, so an existing hint file is
+/// always a complete one.
+pub(crate) fn write_hint_file(
+    env: &dyn Env,
+    dbpath: PathBuf,
+    file_id: FileId,
+    entries: &[HintEntry],
+) -> DBResult<()> {
+    let tmp_path = FileType::Temp.get_full_filepath(dbpath.clone(), file_id);
+    let hint_path = FileType::Hint.get_full_filepath(dbpath.clone(), file_id);
+    let data = encode_hint_entries(entries);
+    write_file_atomic(env, &tmp_path, &hint_path, &data)
+        .and_then(|_| env.sync_dir(&dbpath))
+        .map_err(from_io_error)
+}
+
+/// Load the hint file of the log file `file_id`, `None` if there is none.
+pub(crate) fn read_hint_file(
+    env: &dyn Env,
+    dbpath: PathBuf,
+    file_id: FileId,
+) -> DBResult<Option<Vec<HintEntry>>> {
+    let hint_path = FileType::Hint.get_full_filepath(dbpath, file_id);
+    let data = match read_file(env, &hint_path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(from_io_error(e)),
+    };
+    decode_hint_entries(file_id, &data).map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_hint_entries, encode_hint_entries, HintEntry};
+    use crate::dbfile::EntryHandle;
+    use crate::model::OpType;
+
+    #[test]
+    fn test_encode_decode() {
+        let entries = vec![
+            HintEntry {
+                op_type: OpType::Put,
+                key: b"name".to_vec(),
+                handle: EntryHandle {
+                    file_id: 3,
+                    offset: 0,
+                    length: 33,
+                },
+                seq: 7,
+                ts: 90,
+                expire_at: 100,
+            },
+            HintEntry {
+                op_type: OpType::Del,
+                key: b"age".to_vec(),
+                handle: EntryHandle {
+                    file_id: 3,
+                    offset: 33,
+                    length: 24,
+                },
+                seq: 8,
+                ts: 95,
+                expire_at: 0,
+            },
+        ];
+        let mut data = encode_hint_entries(&entries);
+        assert_eq!(decode_hint_entries(3, &data).unwrap(), entries);
+        assert_eq!(
+            decode_hint_entries(3, &encode_hint_entries(&[])).unwrap(),
+            vec![]
+        );
+
+        data[5] ^= 1;
+        assert!(decode_hint_entries(3, &data).is_err());
+        assert!(decode_hint_entries(3, &data[..data.len() - 1]).is_err());
+    }
+}
@@ -0,0 +1,208 @@
+use std::ops::{Bound, RangeBounds};
+use std::sync::{Arc, RwLock};
+
+use crate::cache::RowCache;
+use crate::db::{read_value, ReadState};
+use crate::dbfile::{EntryHandle, LogFile};
+use crate::errors::DBResult;
+use crate::model::now_millis;
+use crate::options::ReadOptions;
+
+/// A cursor over the keys of a `BitcaskDB` in order, limited to a range.
+///
+/// Each move looks up the index under a short read lock, so writes go on
+/// while iterating and a move may see keys written after the iterator was
+/// created, unless it reads a snapshot. The value is only read from the
+/// log when `value` is called.
+pub struct DBIterator {
+    read_state: Arc<RwLock<ReadState>>,
+    row_cache: Arc<RowCache>,
+    options: ReadOptions,
+    bounds: (Bound<Vec<u8>>, Bound<Vec<u8>>),
+    /// the key the iterator is at, `None` if it is not valid.
+    current: Option<(Vec<u8>, EntryHandle, Arc<LogFile>)>,
+}
+
+impl DBIterator {
+    pub(crate) fn new(
+        read_state: Arc<RwLock<ReadState>>,
+        row_cache: Arc<RowCache>,
+        options: ReadOptions,
+        bounds: (Bound<Vec<u8>>, Bound<Vec<u8>>),
+    ) -> Self {
+        Self {
+            read_state,
+            row_cache,
+            options,
+            bounds,
+            current: None,
+        }
+    }
+
+    /// Whether the iterator is at a key, `key` and `value` panic otherwise.
+    pub fn valid(&self) -> bool {
+        self.current.is_some()
+    }
+
+    /// Move to the first key in the range.
+    pub fn seek_to_first(&mut self) {
+        let start = self.bounds.0.clone();
+        self.move_to((start, Bound::Unbounded), true);
+    }
+
+    /// Move to the last key in the range.
+    pub fn seek_to_last(&mut self) {
+        let end = self.bounds.1.clone();
+        self.move_to((Bound::Unbounded, end), false);
+    }
+
+    /// Move to the first key in the range which is at or past `target`.
+    pub fn seek(&mut self, target: &[u8]) {
+        let start = match &self.bounds.0 {
+            Bound::Included(x) | Bound::Excluded(x) if target <= x.as_slice() => {
+                self.bounds.0.clone()
+            }
+            _ => Bound::Included(target.to_vec()),
+        };
+        self.move_to((start, Bound::Unbounded), true);
+    }
+
+    /// Move to the next key, the iterator must be valid.
+    pub fn next(&mut self) {
+        let key = self.key().to_vec();
+        self.move_to((Bound::Excluded(key), Bound::Unbounded), true);
+    }
+
+    /// Move to the previous key, the iterator must be valid.
+    pub fn prev(&mut self) {
+        let key = self.key().to_vec();
+        self.move_to((Bound::Unbounded, Bound::Excluded(key)), false);
+    }
+
+    pub fn key(&self) -> &[u8] {
+        &self.current.as_ref().expect("iterator is not valid").0
+    }
+
+    /// Read the value of the current key, it is the value the key had
+    /// when the iterator moved to it.
+    pub fn value(&self) -> DBResult<Vec<u8>> {
+        let (_, handle, file) = self.current.as_ref().expect("iterator is not valid");
+        read_value(&self.row_cache, &self.options, file, *handle)
+    }
+
+    /// Position at the first live key of `keys` in the given direction,
+    /// or become invalid if there is none in the range. Expired keys are
+    /// skipped.
+    fn move_to(&mut self, keys: (Bound<Vec<u8>>, Bound<Vec<u8>>), forward: bool) {
+        let now = now_millis();
+        let read_state = self.read_state.read().unwrap();
+        let mut range = read_state.mem_index.range(keys);
+        let found = std::iter::from_fn(|| {
+            if forward {
+                range.next()
+            } else {
+                range.next_back()
+            }
+        })
+        .take_while(|(key, _)| self.bounds.contains(*key))
+        .find(|(_, entry)| !entry.is_expired(now));
+        self.current = found.map(|(key, entry)| {
+            let (handle, file) = read_state.locate(&entry.handle);
+            (key.clone(), handle, file)
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::{BitcaskDB, DBIterator, MemEnv, Options, ReadOptions, WriteOptions};
+
+    fn collect_keys(iter: &mut DBIterator) -> Vec<Vec<u8>> {
+        let mut keys = vec![];
+        while iter.valid() {
+            keys.push(iter.key().to_vec());
+            iter.next();
+        }
+        keys
+    }
+
+    #[test]
+    fn test_iterate() {
+        let opts = Options {
+            target_file_size: 128,
+            env: Arc::new(MemEnv::new()),
+            ..Default::default()
+        };
+        let db = BitcaskDB::open("/db", opts).unwrap();
+        for key in ["b", "a", "c", "d", "e"] {
+            db.put(WriteOptions::default(), key.as_bytes(), key.as_bytes())
+                .unwrap();
+        }
+        db.put(WriteOptions::default(), b"c", b"c-new").unwrap();
+        db.delete(WriteOptions::default(), b"d").unwrap();
+
+        let mut iter = db.iter(ReadOptions::default());
+        assert!(!iter.valid());
+        iter.seek_to_first();
+        assert_eq!(collect_keys(&mut iter), [b"a", b"b", b"c", b"e"]);
+
+        iter.seek_to_last();
+        assert_eq!(iter.key(), b"e");
+        iter.prev();
+        assert_eq!(iter.key(), b"c");
+        assert_eq!(iter.value().unwrap(), b"c-new");
+        iter.seek(b"bb");
+        assert_eq!(iter.key(), b"c");
+        iter.seek(b"f");
+        assert!(!iter.valid());
+
+        // the value survives the merge of the file it was in
+        iter.seek(b"a");
+        db.compact().unwrap();
+        assert_eq!(iter.value().unwrap(), b"a");
+
+        let mut iter = db.range(ReadOptions::default(), "b".."e");
+        assert_eq!(collect_keys(&mut iter), [b"b", b"c"]);
+        let mut iter = db.range(ReadOptions::default(), b"b".to_vec()..=b"e".to_vec());
+        iter.seek_to_last();
+        assert_eq!(iter.key(), b"e");
+        iter.seek(b"a");
+        assert_eq!(iter.key(), b"b");
+        iter.prev();
+        assert!(!iter.valid());
+    }
+
+    #[test]
+    fn test_prefix() {
+        let opts = Options {
+            env: Arc::new(MemEnv::new()),
+            ..Default::default()
+        };
+        let db = BitcaskDB::open("/db", opts).unwrap();
+        let keys: [&[u8]; 7] = [
+            b"tenant1/a",
+            b"tenant1/b",
+            b"tenant10",
+            b"tenant2/a",
+            b"\xff\xff",
+            b"\xff\xff\x01",
+            b"\xff",
+        ];
+        for key in keys {
+            db.put(WriteOptions::default(), key, b"v").unwrap();
+        }
+
+        let mut iter = db.prefix(ReadOptions::default(), b"tenant1/");
+        assert_eq!(collect_keys(&mut iter), [b"tenant1/a", b"tenant1/b"]);
+        let mut iter = db.prefix(ReadOptions::default(), b"tenant3/");
+        assert!(!iter.valid());
+        assert!(collect_keys(&mut iter).is_empty());
+        let mut iter = db.prefix(ReadOptions::default(), b"\xff\xff");
+        assert_eq!(
+            collect_keys(&mut iter),
+            [b"\xff\xff".to_vec(), b"\xff\xff\x01".to_vec()]
+        );
+    }
+}
@@ -1,29 +1,45 @@
-#![feature(cell_update)]
-
+mod cache;
 #[allow(dead_code)]
-
 mod db;
-mod cache;
 mod dbfile;
+mod env;
 mod errors;
+#[cfg(test)]
+mod fault_env;
 mod filename;
+mod hint;
+mod iterator;
 mod model;
 mod options;
+mod snapshot;
 mod versionset;
 mod writebatch;
 
-pub use db::BitcaskDB;
-pub use options::{Options, ReadOptions, WriteOptions};
+pub use cache::CacheStats;
+pub use db::{BitcaskDB, SyncStats};
+pub use dbfile::FileId;
+pub use env::{Env, EnvFile, FileLock, MemEnv, OpenMode, PosixEnv};
+pub use errors::{DBError, DBResult, ErrorKind};
+pub use iterator::DBIterator;
+pub use model::ValueMeta;
+pub use options::{CorruptionPolicy, Options, ReadOptions, SyncPolicy, WriteOptions};
+pub use snapshot::Snapshot;
 pub use writebatch::WriteBatch;
 
 #[cfg(test)]
 mod tests {
-    use crate::{BitcaskDB, Options, ReadOptions, WriteOptions};
+    use std::path::Path;
+    use std::sync::Arc;
+
+    use crate::{BitcaskDB, DBError, Env, FileId, MemEnv, Options, ReadOptions, WriteOptions};
 
     #[test]
     fn it_works() {
-        let opts = Options::default();
-        let bitcask = BitcaskDB::open("/tmp/bitcask001", opts).unwrap();
+        let opts = Options {
+            env: Arc::new(MemEnv::new()),
+            ..Default::default()
+        };
+        let bitcask = BitcaskDB::open("/bitcask001", opts).unwrap();
         let value = bitcask.get(ReadOptions::default(), b"__name__");
         assert!(value.is_ok());
         assert!(value.unwrap().is_none());
@@ -33,4 +49,52 @@ mod tests {
             .put(WriteOptions::default(), b"name", b"guoxiang")
             .unwrap();
     }
+
+    #[test]
+    fn test_compact_files() {
+        let env = Arc::new(MemEnv::new());
+        let opts = Options {
+            target_file_size: 64,
+            env: env.clone(),
+            ..Default::default()
+        };
+        let db = BitcaskDB::open("/db", opts).unwrap();
+        for i in 0..20 {
+            let key = format!("key{}", i % 4);
+            db.put(WriteOptions::default(), key.as_bytes(), key.as_bytes())
+                .unwrap();
+        }
+        let frozen = db.freeze_file_ids();
+        assert!(frozen.len() > 2);
+
+        // only freeze files can be merged
+        let active: FileId = env
+            .list(Path::new("/db"))
+            .unwrap()
+            .iter()
+            .filter_map(|x| x.strip_suffix(".dat")?.parse().ok())
+            .max()
+            .unwrap();
+        assert!(!frozen.contains(&active));
+        for file_ids in [vec![active], vec![frozen[0], 999]] {
+            assert!(matches!(
+                db.compact_files(&file_ids),
+                Err(DBError::InvalidArgument(_))
+            ));
+        }
+        assert_eq!(db.freeze_file_ids(), frozen);
+
+        // the chosen files are replaced, the others stay
+        db.compact_files(&frozen[..2]).unwrap();
+        let after = db.freeze_file_ids();
+        assert!(!after.contains(&frozen[0]) && !after.contains(&frozen[1]));
+        assert!(frozen[2..].iter().all(|x| after.contains(x)));
+        for i in 0..4 {
+            let key = format!("key{}", i);
+            assert_eq!(
+                db.get(ReadOptions::default(), key.as_bytes()).unwrap(),
+                Some(key.into_bytes())
+            );
+        }
+    }
 }
@@ -1,5 +1,9 @@
-use crate::errors::DBResult;
+use crate::errors::{self, DBResult};
 use std::io::Write;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// crc(4)+seq(8)+ts(8)+expire_at(8)+keysz(4)+valsz(4)
+pub(crate) const ENTRY_HEADER_SIZE: usize = 36;
 
 #[repr(u8)]
 #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
@@ -9,22 +13,32 @@ pub(crate) enum OpType {
     Del,
 }
 
-impl From<u8> for OpType {
-    fn from(value: u8) -> Self {
+impl TryFrom<u8> for OpType {
+    type Error = errors::DBError;
+
+    fn try_from(value: u8) -> DBResult<Self> {
         match value {
-            0 => OpType::Put,
-            1 => OpType::Del,
-            _ => panic!("invalid u8"),
+            0 => Ok(OpType::Put),
+            1 => Ok(OpType::Del),
+            _ => Err(errors::corruption("invalid op type")),
         }
     }
 }
 
-#[derive(Debug, Default, PartialEq, Eq)]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
 pub(crate) struct OwnedEntry {
     pub(crate) op_type: OpType,
     pub(crate) key: Vec<u8>,
     pub(crate) value: Option<Vec<u8>>,
+    /// the write time in milliseconds since the unix epoch, assigned
+    /// together with `seq` when the record is written.
     pub(crate) ts: Option<u64>,
+    /// the sequence number of the write, of two records of a key the one
+    /// with the larger sequence wins.
+    pub(crate) seq: u64,
+    /// the expiry time in milliseconds since the unix epoch, 0 if the
+    /// record never expires.
+    pub(crate) expire_at: u64,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -33,6 +47,8 @@ pub(crate) struct RefEntry<'a, 'b> {
     pub(crate) key: &'a [u8],
     pub(crate) value: Option<&'b [u8]>,
     pub(crate) ts: Option<u64>,
+    pub(crate) seq: u64,
+    pub(crate) expire_at: u64,
 }
 
 impl OwnedEntry {
@@ -42,83 +58,192 @@ impl OwnedEntry {
         'c: 'b,
     {
         RefEntry::<'a, 'b> {
-            op_type: self.op_type.clone(),
+            op_type: self.op_type,
             key: &self.key,
             value: self.value.as_ref().map(|x| x.as_ref()),
-            ts: self.ts.clone(),
+            ts: self.ts,
+            seq: self.seq,
+            expire_at: self.expire_at,
         }
     }
 
-    pub(crate) fn decode_from_bytes(bytes: &[u8], _verify_crc: bool) -> DBResult<OwnedEntry> {
-        // crc(4)+ts(8)+keysz(4)+valsz(4)+key+optype(1)+val
-        assert!(bytes.len() > 20);
-        let mut entry = OwnedEntry::default();
-        // skip crc todo
-        entry.ts = Some(u64::from_be_bytes((bytes[4..12]).try_into().unwrap()));
+    pub(crate) fn decode_from_bytes(bytes: &[u8], verify_crc: bool) -> DBResult<OwnedEntry> {
+        // crc(4)+seq(8)+ts(8)+expire_at(8)+keysz(4)+valsz(4)+key+optype(1)+val
+        if bytes.len() <= ENTRY_HEADER_SIZE || bytes.len() as u64 != decode_entry_length(bytes) {
+            return Err(errors::corruption("bad entry length"));
+        }
+        if verify_crc {
+            let expected = u32::from_be_bytes(bytes[0..4].try_into().unwrap());
+            if crc32fast::hash(&bytes[4..]) != expected {
+                return Err(errors::corruption("entry checksum mismatch"));
+            }
+        }
+        let mut entry = OwnedEntry {
+            seq: u64::from_be_bytes(bytes[4..12].try_into().unwrap()),
+            ts: Some(u64::from_be_bytes((bytes[12..20]).try_into().unwrap())),
+            expire_at: u64::from_be_bytes(bytes[20..28].try_into().unwrap()),
+            ..Default::default()
+        };
         // keysz
-        let keysz = u32::from_be_bytes(bytes[12..16].try_into().unwrap());
-        let valsz = u32::from_be_bytes(bytes[16..20].try_into().unwrap());
-        assert!(bytes.len() >= (20 + keysz + valsz + 1) as usize);
+        let keysz = u32::from_be_bytes(bytes[28..32].try_into().unwrap()) as usize;
+        let valsz = u32::from_be_bytes(bytes[32..36].try_into().unwrap());
         // read key
         entry
             .key
-            .extend_from_slice(&bytes[20..(20 + keysz as usize)]);
-        entry.op_type = OpType::from(bytes[20 + keysz as usize]);
+            .extend_from_slice(&bytes[ENTRY_HEADER_SIZE..ENTRY_HEADER_SIZE + keysz]);
+        entry.op_type = OpType::try_from(bytes[ENTRY_HEADER_SIZE + keysz])?;
         if entry.op_type == OpType::Del {
-            assert!(valsz == 0);
+            if valsz != 0 {
+                return Err(errors::corruption("deletion with a value"));
+            }
             entry.value = None;
         } else {
             let mut val = vec![];
-            val.extend_from_slice(&bytes[(20 + keysz + 1) as usize..]);
+            val.extend_from_slice(&bytes[ENTRY_HEADER_SIZE + keysz + 1..]);
             entry.value = Some(val);
         }
         Ok(entry)
     }
 }
 
-impl<'a, 'b> RefEntry<'a, 'b> {
-    /// |crc|ts|ksz|vsz|key|op|value|
+/// When and in which order a value was written, see
+/// `BitcaskDB::get_with_meta`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ValueMeta {
+    /// the write time in milliseconds since the unix epoch.
+    pub timestamp: u64,
+    /// strictly increasing over all the writes of a db.
+    pub sequence: u64,
+}
+
+/// Milliseconds since the unix epoch, the unit of the expiry times.
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |x| x.as_millis() as u64)
+}
+
+/// The expiry time of a record written now which lives for `ttl`.
+pub(crate) fn expire_time(ttl: Duration) -> u64 {
+    // 0 means never, a record always expires at some point after it
+    let ttl = u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX);
+    now_millis().saturating_add(ttl).max(1)
+}
+
+/// Whether a record with the expiry time `expire_at` is expired at `now`.
+pub(crate) fn is_expired(expire_at: u64, now: u64) -> bool {
+    expire_at != 0 && expire_at <= now
+}
+
+/// Returns the total encoded length of the entry described by `header`,
+/// which must contain at least `ENTRY_HEADER_SIZE` bytes.
+pub(crate) fn decode_entry_length(header: &[u8]) -> u64 {
+    assert!(header.len() >= ENTRY_HEADER_SIZE);
+    let keysz = u32::from_be_bytes(header[28..32].try_into().unwrap());
+    let valsz = u32::from_be_bytes(header[32..36].try_into().unwrap());
+    ENTRY_HEADER_SIZE as u64 + keysz as u64 + 1 + valsz as u64
+}
+
+impl RefEntry<'_, '_> {
+    /// |crc|seq|ts|expire_at|ksz|vsz|key|op|value|
     pub(crate) fn encode_to_bytes(&self) -> Vec<u8> {
-        assert!(self.key.len() < 2 ^ 32);
-        assert!(self.value.as_ref().map_or(0, |x| x.len()) < 2 ^ 32);
+        let mut data = vec![];
+        self.encode_to(&mut data);
+        data
+    }
+
+    /// Append the encoded record to `data`.
+    pub(crate) fn encode_to(&self, data: &mut Vec<u8>) {
         if self.op_type == OpType::Del {
             assert!(self.value.is_none());
         }
+        encode_record(
+            data,
+            [self.seq, self.ts.unwrap_or(0), self.expire_at],
+            self.key,
+            self.op_type as u8,
+            self.value.unwrap_or_default(),
+        );
+    }
+}
 
-        let mut data = vec![];
-        // CRC32
-        data.write(&[0, 0, 0, 0]).unwrap();
+fn encode_record(data: &mut Vec<u8>, seq_ts_expire: [u64; 3], key: &[u8], op: u8, value: &[u8]) {
+    assert!(key.len() <= u32::MAX as usize);
+    assert!(value.len() <= u32::MAX as usize);
+    let start = data.len();
+    // CRC32, filled after the rest is encoded
+    data.write_all(&[0, 0, 0, 0]).unwrap();
+    for x in seq_ts_expire {
+        data.write_all(&x.to_be_bytes()).unwrap();
+    }
+    data.write_all(&(key.len() as u32).to_be_bytes()).unwrap();
+    data.write_all(&(value.len() as u32).to_be_bytes()).unwrap();
+    data.write_all(key).unwrap();
+    data.push(op);
+    data.write_all(value).unwrap();
+    let crc = crc32fast::hash(&data[start + 4..]);
+    data[start..start + 4].copy_from_slice(&crc.to_be_bytes());
+}
 
-        // ts
-        let ts = self.ts.clone().unwrap_or(0);
-        let ts_bytes = ts.to_be_bytes();
-        assert!(ts_bytes.len() == 8);
-        data.write(&ts_bytes).unwrap();
+/// The op byte of a batch header. It is not an `OpType`, a header frames
+/// the records of a `WriteBatch` and never reaches the index.
+const BATCH_HEADER_OP: u8 = 0xff;
 
-        // keysz
-        let keysz = (self.key.len() as u32).to_be_bytes();
-        assert!(keysz.len() == 4);
-        data.write(&keysz).unwrap();
-
-        // valsz
-        let valsz = self.value.as_ref().map_or(0, |x| x.len());
-        let valsz = (valsz as u32).to_be_bytes();
-        assert!(valsz.len() == 4);
-        data.write(&valsz).unwrap();
-
-        // key
-        data.write_all(self.key).unwrap();
-        // op_type
-        data.push(self.op_type as u8);
-        // value
-        if self.value.is_some() {
-            // Clone a reference not all data.
-            data.write_all(self.value.clone().unwrap()).unwrap();
+/// A batch header is a record with no key and count(4)+length(8)+crc(4)
+/// as the value.
+pub(crate) const BATCH_HEADER_SIZE: usize = ENTRY_HEADER_SIZE + 1 + 16;
+
+/// The record written before the records of a `WriteBatch`, recovery
+/// applies the batch only if all of its `length` bytes are there and
+/// match `crc`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct BatchHeader {
+    /// the sequence of the first record of the batch.
+    pub(crate) seq: u64,
+    pub(crate) count: u32,
+    pub(crate) length: u64,
+    pub(crate) crc: u32,
+}
+
+impl BatchHeader {
+    /// The header of the encoded records `body`.
+    pub(crate) fn new(seq: u64, count: u32, body: &[u8]) -> Self {
+        Self {
+            seq,
+            count,
+            length: body.len() as u64,
+            crc: crc32fast::hash(body),
         }
-        data
     }
-}
 
-pub(crate) trait EntryConsumer {
-    fn consume(&mut self, entry: OwnedEntry);
+    pub(crate) fn encode_to(&self, data: &mut Vec<u8>) {
+        let mut value = Vec::with_capacity(16);
+        value.extend_from_slice(&self.count.to_be_bytes());
+        value.extend_from_slice(&self.length.to_be_bytes());
+        value.extend_from_slice(&self.crc.to_be_bytes());
+        encode_record(data, [self.seq, 0, 0], &[], BATCH_HEADER_OP, &value);
+    }
+
+    /// Decode a complete record, `None` if it is not a batch header.
+    pub(crate) fn decode(bytes: &[u8], verify_crc: bool) -> DBResult<Option<BatchHeader>> {
+        if bytes.len() != BATCH_HEADER_SIZE
+            || bytes[28..32] != [0; 4]
+            || bytes[ENTRY_HEADER_SIZE] != BATCH_HEADER_OP
+        {
+            return Ok(None);
+        }
+        if verify_crc {
+            let expected = u32::from_be_bytes(bytes[0..4].try_into().unwrap());
+            if crc32fast::hash(&bytes[4..]) != expected {
+                return Err(errors::corruption("batch header checksum mismatch"));
+            }
+        }
+        let value = &bytes[ENTRY_HEADER_SIZE + 1..];
+        Ok(Some(BatchHeader {
+            seq: u64::from_be_bytes(bytes[4..12].try_into().unwrap()),
+            count: u32::from_be_bytes(value[0..4].try_into().unwrap()),
+            length: u64::from_be_bytes(value[4..12].try_into().unwrap()),
+            crc: u32::from_be_bytes(value[12..16].try_into().unwrap()),
+        }))
+    }
 }
@@ -1,11 +1,74 @@
-use std::ops::Deref;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::env::{Env, PosixEnv};
+use crate::snapshot::Snapshot;
+
+/// What recovery does when it meets a broken record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CorruptionPolicy {
+    /// Truncate the file at the last good record and keep going.
+    Truncate,
+    /// Refuse to open the db.
+    Fail,
+}
+
+/// When the db syncs the active log file by itself, besides the writes
+/// asking for it with `WriteOptions::sync`.
+///
+/// A write which is not synced survives a crash of the process, the os
+/// still has it, but not a crash of the machine. The loss window of each
+/// policy is what a crash of the machine may lose, see
+/// `BitcaskDB::sync_stats` for the writes in it at a given time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Only the writes with `WriteOptions::sync` are synced, the loss
+    /// window is whatever the os has not written back yet.
+    Never,
+    /// Every write is synced before it returns, nothing is lost.
+    Always,
+    /// Every n-th write is synced along with the ones before it, up to
+    /// n - 1 writes are lost.
+    EveryN(u64),
+    /// A background thread syncs the writes of the last interval, the
+    /// writes of up to an interval and the time of a sync are lost.
+    EveryInterval(Duration),
+    /// A write is synced once the bytes written since the last sync reach
+    /// the threshold, less than that many bytes of writes are lost.
+    BytesWritten(u64),
+}
 
 #[derive(Debug, Clone)]
 pub struct Options {
     pub create_if_missing: bool,
     pub error_if_exists: bool,
     pub target_file_size: u64,
+    /// The bytes of values kept in memory by the row cache, 0 disables it.
     pub row_cache_size: u64,
+    /// Applied to a partial or corrupted last record of the newest log
+    /// file, which is what a crash in the middle of a write leaves behind.
+    pub tail_corruption: CorruptionPolicy,
+    /// Applied to a broken record anywhere else, truncating there drops
+    /// every record after it.
+    pub mid_file_corruption: CorruptionPolicy,
+    /// The manifest is rolled to a new file holding a single snapshot
+    /// record once it grows beyond this size.
+    pub max_manifest_file_size: u64,
+    /// When CURRENT is unreadable or points to a missing or broken
+    /// manifest, open from the newest valid MANIFEST in the directory
+    /// instead of failing. Edits only recorded in the lost manifest are
+    /// gone, so this is off by default. The log and rewrite files only the
+    /// lost manifest referenced are renamed to `<name>.lost`, not deleted.
+    pub fallback_to_newest_manifest: bool,
+    /// Syncs the active log file without waiting for `WriteOptions::sync`.
+    /// A log file is always synced when it is frozen, whatever the policy.
+    pub sync_policy: SyncPolicy,
+    /// `flush_all` also freezes the active file, which writes its hint,
+    /// so that the next open loads the hint instead of scanning the file.
+    /// The writes after the flush go to a new log file.
+    pub hint_on_flush: bool,
+    /// The file system of the db, `PosixEnv` by default.
+    pub env: Arc<dyn Env>,
 }
 
 impl Default for Options {
@@ -15,6 +78,13 @@ impl Default for Options {
             error_if_exists: false,
             target_file_size: 32 * 1024 * 1024,
             row_cache_size: 0, // disable row cache
+            tail_corruption: CorruptionPolicy::Truncate,
+            mid_file_corruption: CorruptionPolicy::Fail,
+            max_manifest_file_size: 4 * 1024 * 1024,
+            fallback_to_newest_manifest: false,
+            sync_policy: SyncPolicy::Never,
+            hint_on_flush: false,
+            env: Arc::new(PosixEnv),
         }
     }
 }
@@ -29,6 +99,8 @@ impl AsRef<Self> for Options {
 pub struct ReadOptions {
     pub verify_checksum: bool,
     pub fill_cache: bool,
+    /// Read the db as of this snapshot instead of its latest state.
+    pub snapshot: Option<Snapshot>,
 }
 
 impl Default for ReadOptions {
@@ -36,17 +108,12 @@ impl Default for ReadOptions {
         Self {
             verify_checksum: false,
             fill_cache: true,
+            snapshot: None,
         }
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct WriteOptions {
     pub sync: bool,
 }
-
-impl Default for WriteOptions {
-    fn default() -> Self {
-        Self { sync: false }
-    }
-}
@@ -0,0 +1,31 @@
+use std::fmt;
+
+use crate::db::ReadState;
+
+/// A point-in-time view of a `BitcaskDB`, reads with it set in
+/// `ReadOptions::snapshot` see the db as it was when it was taken.
+///
+/// The files the view points to are kept until the last clone of the
+/// snapshot is dropped, even if a compaction merges them meanwhile.
+#[derive(Clone)]
+pub struct Snapshot {
+    state: ReadState,
+}
+
+impl Snapshot {
+    pub(crate) fn new(state: ReadState) -> Self {
+        Self { state }
+    }
+
+    pub(crate) fn state(&self) -> &ReadState {
+        &self.state
+    }
+}
+
+impl fmt::Debug for Snapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Snapshot")
+            .field("num_keys", &self.state.mem_index.len())
+            .finish()
+    }
+}
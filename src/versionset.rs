@@ -1,16 +1,39 @@
-use std::{io::Write, path::PathBuf, sync::Arc};
+use std::{path::PathBuf, sync::Arc};
 
 use crate::dbfile::{FileId, INVALID_FILE_ID};
-use crate::errors::{from_io_error, DBResult};
+use crate::env::{read_file, write_all_at, write_file_atomic, EnvFile, OpenMode};
+use crate::errors::{corruption, from_io_error, io_error, DBResult};
 use crate::filename::FileType;
+use crate::options::Options;
+
+/// length(4)+crc(4)
+const MANIFEST_RECORD_HEADER_SIZE: usize = 8;
+
+// tags of the fields in an encoded version edit.
+const TAG_NEW_ACTIVE_FILE: u8 = 1;
+const TAG_NEED_FREEZE: u8 = 2;
+const TAG_COMPACT_INPUT_IMM: u8 = 3;
+const TAG_COMPACT_OUTPUT_IMM: u8 = 4;
+const TAG_NEXT_LOGFILE_ID: u8 = 5;
+const TAG_LAST_SEQUENCE: u8 = 6;
 
 pub(crate) struct VersionSet {
     dbpath: PathBuf,
     next_logfile_id: FileId,
+    /// the largest sequence number handed out, saved with every edit so
+    /// that sequences never go back even if compaction drops the records.
+    last_sequence: u64,
     manifest_file_id: FileId,
+    manifest_file: Option<Box<dyn EnvFile>>,
+    manifest_size: u64,
+    options: Arc<Options>,
     current: Arc<Version>,
+    /// set when recovery fell back to an older manifest than CURRENT
+    /// named, see `Options::fallback_to_newest_manifest`.
+    used_fallback: bool,
 }
 
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
 pub(crate) struct Version {
     pub(crate) mut_id: FileId,
     pub(crate) imm_ids: Vec<FileId>,
@@ -19,22 +42,182 @@ pub(crate) struct Version {
     // next: Option<Arc<Version>>,
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, PartialEq, Eq)]
 pub(crate) struct VersionEdit {
     pub(crate) new_active_file: Option<FileId>,
     pub(crate) need_freeze: Option<FileId>,
     pub(crate) compact_input_imm: Option<Vec<FileId>>,
     pub(crate) compact_output_imm: Option<Vec<FileId>>,
+    pub(crate) next_logfile_id: Option<FileId>,
+    pub(crate) last_sequence: Option<u64>,
+}
+
+impl Version {
+    /// All the files referenced by this version, the active one included.
+    pub(crate) fn live_file_ids(&self) -> Vec<FileId> {
+        let mut ids = self.imm_ids.clone();
+        if self.mut_id != INVALID_FILE_ID {
+            ids.push(self.mut_id);
+        }
+        ids.sort_unstable();
+        ids
+    }
+
+    /// The version after `edit`, which may come from a broken manifest.
+    fn apply(&self, edit: &VersionEdit) -> DBResult<Version> {
+        let mut v = self.clone();
+        if let Some(id) = edit.need_freeze {
+            if id != v.mut_id {
+                return Err(corruption(&format!(
+                    "version edit freezes file {} which is not the active one",
+                    id
+                )));
+            }
+            v.imm_ids.push(id);
+            v.mut_id = INVALID_FILE_ID;
+        }
+        if let Some(id) = edit.new_active_file {
+            v.mut_id = id;
+        }
+        if let Some(ids) = &edit.compact_input_imm {
+            v.imm_ids.retain(|x| !ids.contains(x));
+        }
+        if let Some(ids) = &edit.compact_output_imm {
+            v.imm_ids.extend_from_slice(ids);
+        }
+        v.imm_ids.sort_unstable();
+        Ok(v)
+    }
+}
+
+impl VersionEdit {
+    /// A single edit which rebuilds `version` from scratch, it is the
+    /// first record of every manifest.
+    fn snapshot(version: &Version, next_logfile_id: FileId, last_sequence: u64) -> VersionEdit {
+        VersionEdit {
+            new_active_file: Some(version.mut_id).filter(|x| *x != INVALID_FILE_ID),
+            compact_output_imm: Some(version.imm_ids.clone()),
+            next_logfile_id: Some(next_logfile_id),
+            last_sequence: Some(last_sequence),
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut data = vec![];
+        let mut put_id = |tag: u8, id: &Option<FileId>| {
+            if let Some(id) = id {
+                data.push(tag);
+                data.extend_from_slice(&id.to_be_bytes());
+            }
+        };
+        put_id(TAG_NEW_ACTIVE_FILE, &self.new_active_file);
+        put_id(TAG_NEED_FREEZE, &self.need_freeze);
+        put_id(TAG_NEXT_LOGFILE_ID, &self.next_logfile_id);
+        put_id(TAG_LAST_SEQUENCE, &self.last_sequence);
+        let mut put_ids = |tag: u8, ids: &Option<Vec<FileId>>| {
+            if let Some(ids) = ids {
+                data.push(tag);
+                data.extend_from_slice(&(ids.len() as u32).to_be_bytes());
+                for id in ids {
+                    data.extend_from_slice(&id.to_be_bytes());
+                }
+            }
+        };
+        put_ids(TAG_COMPACT_INPUT_IMM, &self.compact_input_imm);
+        put_ids(TAG_COMPACT_OUTPUT_IMM, &self.compact_output_imm);
+        data
+    }
+
+    pub(crate) fn decode(mut data: &[u8]) -> DBResult<VersionEdit> {
+        fn get_u64(data: &mut &[u8]) -> DBResult<u64> {
+            if data.len() < 8 {
+                return Err(corruption("version edit truncated"));
+            }
+            let (x, rest) = data.split_at(8);
+            *data = rest;
+            Ok(u64::from_be_bytes(x.try_into().unwrap()))
+        }
+        fn get_ids(data: &mut &[u8]) -> DBResult<Vec<FileId>> {
+            if data.len() < 4 {
+                return Err(corruption("version edit truncated"));
+            }
+            let (n, rest) = data.split_at(4);
+            *data = rest;
+            let n = u32::from_be_bytes(n.try_into().unwrap());
+            (0..n).map(|_| get_u64(data)).collect()
+        }
+
+        let mut edit = VersionEdit::default();
+        while let Some((&tag, rest)) = data.split_first() {
+            data = rest;
+            match tag {
+                TAG_NEW_ACTIVE_FILE => edit.new_active_file = Some(get_u64(&mut data)?),
+                TAG_NEED_FREEZE => edit.need_freeze = Some(get_u64(&mut data)?),
+                TAG_NEXT_LOGFILE_ID => edit.next_logfile_id = Some(get_u64(&mut data)?),
+                TAG_LAST_SEQUENCE => edit.last_sequence = Some(get_u64(&mut data)?),
+                TAG_COMPACT_INPUT_IMM => edit.compact_input_imm = Some(get_ids(&mut data)?),
+                TAG_COMPACT_OUTPUT_IMM => edit.compact_output_imm = Some(get_ids(&mut data)?),
+                _ => return Err(corruption("unknown tag in version edit")),
+            }
+        }
+        if edit.next_logfile_id == Some(INVALID_FILE_ID) {
+            return Err(corruption("invalid next file id in version edit"));
+        }
+        Ok(edit)
+    }
+}
+
+/// |len|crc|payload|, the crc covers the payload only.
+fn encode_manifest_record(payload: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(MANIFEST_RECORD_HEADER_SIZE + payload.len());
+    data.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    data.extend_from_slice(&crc32fast::hash(payload).to_be_bytes());
+    data.extend_from_slice(payload);
+    data
+}
+
+/// Split the content of a manifest into record payloads. The second value
+/// is false when the last record is torn, which happens if we crashed in
+/// the middle of `log_and_apply`; that edit was never acknowledged.
+fn decode_manifest_records(mut data: &[u8]) -> DBResult<(Vec<&[u8]>, bool)> {
+    let mut records = vec![];
+    while !data.is_empty() {
+        if data.len() < MANIFEST_RECORD_HEADER_SIZE {
+            return Ok((records, false));
+        }
+        let len = u32::from_be_bytes(data[0..4].try_into().unwrap()) as usize;
+        let crc = u32::from_be_bytes(data[4..8].try_into().unwrap());
+        let end = MANIFEST_RECORD_HEADER_SIZE + len;
+        if data.len() < end {
+            return Ok((records, false));
+        }
+        let payload = &data[MANIFEST_RECORD_HEADER_SIZE..end];
+        if crc32fast::hash(payload) != crc {
+            if data.len() == end {
+                return Ok((records, false));
+            }
+            return Err(corruption("manifest record checksum mismatch"));
+        }
+        records.push(payload);
+        data = &data[end..];
+    }
+    Ok((records, true))
 }
 
 impl VersionSet {
-    pub(crate) fn new(dbpath: PathBuf) -> Self {
-        // Self {
-        //     dbpath: dbpath,
-        //     next_logfile_id: INVALID_FILE_ID + 1,
-        //     manifest_file_id: 0,
-        // }
-        todo!()
+    pub(crate) fn new(dbpath: PathBuf, options: Arc<Options>) -> Self {
+        Self {
+            dbpath,
+            next_logfile_id: INVALID_FILE_ID + 1,
+            last_sequence: 0,
+            manifest_file_id: 0,
+            manifest_file: None,
+            manifest_size: 0,
+            options,
+            current: Arc::new(Version::default()),
+            used_fallback: false,
+        }
     }
 
     pub(crate) fn new_logfile_id(&mut self) -> FileId {
@@ -43,40 +226,509 @@ impl VersionSet {
         last
     }
 
-    pub fn recovery(&self, save_manifest: bool) -> DBResult<()> {
+    /// Make sure `new_logfile_id` never hands out `file_id` again.
+    pub(crate) fn mark_file_id_used(&mut self, file_id: FileId) {
+        if self.next_logfile_id <= file_id {
+            self.next_logfile_id = file_id + 1;
+        }
+    }
+
+    pub(crate) fn last_sequence(&self) -> u64 {
+        self.last_sequence
+    }
+
+    /// Raise the last sequence to `seq`, it never goes back.
+    pub(crate) fn set_last_sequence(&mut self, seq: u64) {
+        self.last_sequence = self.last_sequence.max(seq);
+    }
+
+    /// Whether `recovery` opened from an older manifest, the files only
+    /// the lost one referenced are then unknown to the current version.
+    pub(crate) fn used_fallback(&self) -> bool {
+        self.used_fallback
+    }
+
+    /// Recover the last saved descriptor from persistent storage, a brand
+    /// new manifest is created when the db has none. If `save_manifest`
+    /// is set, the recovered state is always written to a new manifest.
+    pub fn recovery(&mut self, save_manifest: bool) -> DBResult<()> {
+        let mut manifest_ids = vec![];
+        for filename in self.options.env.list(&self.dbpath).map_err(from_io_error)? {
+            match FileType::parse_filename(&filename) {
+                Some((FileType::Manifest, id)) => {
+                    self.mark_file_id_used(id);
+                    manifest_ids.push(id);
+                }
+                Some((_, id)) => self.mark_file_id_used(id),
+                None => {}
+            }
+        }
+
+        let current_path = FileType::Current.get_full_filepath(self.dbpath.clone(), 0);
+        if !self.options.env.exists(&current_path) && manifest_ids.is_empty() {
+            return self.roll_manifest(Version::default());
+        }
+
+        let loaded = self
+            .read_current_file()
+            .and_then(|manifest_id| self.load_manifest(manifest_id));
+        let (version, manifest_size, complete) = match loaded {
+            Ok(loaded) => loaded,
+            Err(e) if !self.options.fallback_to_newest_manifest => return Err(e),
+            Err(e) => {
+                log::warn!("{:?}, fall back to the newest valid manifest", e);
+                let (version, _, _) = self.load_newest_valid_manifest(manifest_ids)?;
+                self.used_fallback = true;
+                return self.roll_manifest(version);
+            }
+        };
+
+        if save_manifest || !complete || manifest_size >= self.options.max_manifest_file_size {
+            return self.roll_manifest(version);
+        }
+        let manifest_path =
+            FileType::Manifest.get_full_filepath(self.dbpath.clone(), version.manifest_id);
+        let file = self
+            .options
+            .env
+            .open(&manifest_path, OpenMode::Existing)
+            .map_err(from_io_error)?;
+        self.manifest_file = Some(file);
+        self.manifest_file_id = version.manifest_id;
+        self.manifest_size = manifest_size;
+        self.current = Arc::new(version);
         Ok(())
     }
 
+    /// Returns the id of the manifest which CURRENT points to.
+    fn read_current_file(&self) -> DBResult<FileId> {
+        let current_path = FileType::Current.get_full_filepath(self.dbpath.clone(), 0);
+        let contents =
+            read_file(self.options.env.as_ref(), &current_path).map_err(from_io_error)?;
+        match std::str::from_utf8(&contents)
+            .ok()
+            .and_then(|x| FileType::parse_filename(x.trim()))
+        {
+            Some((FileType::Manifest, id)) => Ok(id),
+            _ => Err(corruption("CURRENT does not point to a manifest")),
+        }
+    }
+
+    /// Replay the manifest `manifest_id`, returns the version it describes,
+    /// the size of the manifest and whether its last record is complete.
+    fn load_manifest(&mut self, manifest_id: FileId) -> DBResult<(Version, u64, bool)> {
+        let manifest_path = FileType::Manifest.get_full_filepath(self.dbpath.clone(), manifest_id);
+        if !self.options.env.exists(&manifest_path) {
+            return Err(corruption(&format!(
+                "CURRENT points to a missing manifest {}",
+                manifest_path.display()
+            )));
+        }
+        let data = read_file(self.options.env.as_ref(), &manifest_path)
+            .map_err(|e| io_error(format!("read {}", manifest_path.display()), e))?;
+        let (records, complete) = decode_manifest_records(&data)?;
+        if records.is_empty() {
+            return Err(corruption("empty manifest"));
+        }
+
+        let mut version = Version::default();
+        for record in records {
+            let edit = VersionEdit::decode(record)?;
+            version = version.apply(&edit)?;
+            if let Some(id) = edit.next_logfile_id {
+                self.mark_file_id_used(id - 1);
+            }
+            if let Some(seq) = edit.last_sequence {
+                self.set_last_sequence(seq);
+            }
+        }
+        version.manifest_id = manifest_id;
+        Ok((version, data.len() as u64, complete))
+    }
+
+    fn load_newest_valid_manifest(
+        &mut self,
+        mut manifest_ids: Vec<FileId>,
+    ) -> DBResult<(Version, u64, bool)> {
+        manifest_ids.sort_unstable();
+        for manifest_id in manifest_ids.into_iter().rev() {
+            match self.load_manifest(manifest_id) {
+                Ok(loaded) => return Ok(loaded),
+                Err(e) => log::warn!("skip manifest {}: {:?}", manifest_id, e),
+            }
+        }
+        Err(corruption("no valid manifest found"))
+    }
+
     pub(crate) fn current(&self) -> Arc<Version> {
-        // self.dbpath.join(path)
-        todo!()
+        self.current.clone()
     }
+
     // Apply *edit to the current version to form a new descriptor that
     // is both saved to persistent state and installed as the new
-    // current version.  Will release *mu while actually writing to the file.
-    // REQUIRES: *mu is held on entry.
+    // current version.
     // REQUIRES: no other thread concurrently calls LogAndApply()
-    pub fn log_and_apply(&self, _edit: &VersionEdit) -> DBResult<()> {
+    pub fn log_and_apply(&mut self, edit: &mut VersionEdit) -> DBResult<()> {
+        let version = self.current.apply(edit)?;
+        if self.manifest_file.is_none() || self.manifest_size >= self.options.max_manifest_file_size
+        {
+            return self.roll_manifest(version);
+        }
+
+        edit.next_logfile_id = Some(self.next_logfile_id);
+        edit.last_sequence = Some(self.last_sequence);
+        let record = encode_manifest_record(&edit.encode());
+        let file = self.manifest_file.as_ref().unwrap();
+        let written = write_all_at(file.as_ref(), &record, self.manifest_size);
+        if let Err(e) = written.and_then(|_| file.sync()) {
+            // the record may be torn, the next edit goes to a new manifest
+            // rather than after it.
+            self.manifest_file = None;
+            return Err(from_io_error(e));
+        }
+        self.manifest_size += record.len() as u64;
+        self.current = Arc::new(version);
         Ok(())
     }
 
-    ///  Recover the last saved descriptor from persistent storage.
+    /// Write `version` as the snapshot record of a new manifest, switch
+    /// CURRENT to it and install `version` as the current one.
+    fn roll_manifest(&mut self, mut version: Version) -> DBResult<()> {
+        let manifest_id = self.new_logfile_id();
+        let manifest_path = FileType::Manifest.get_full_filepath(self.dbpath.clone(), manifest_id);
+        let snapshot = VersionEdit::snapshot(&version, self.next_logfile_id, self.last_sequence);
+        let record = encode_manifest_record(&snapshot.encode());
+        let file = self
+            .options
+            .env
+            .open(&manifest_path, OpenMode::CreateNew)
+            .map_err(from_io_error)?;
+        let written = write_all_at(file.as_ref(), &record, 0)
+            .and_then(|_| file.sync())
+            .map_err(from_io_error)
+            .and_then(|_| self.write_current_file(manifest_id));
+        if let Err(e) = written {
+            // a db without CURRENT is only a new one if it has no
+            // manifest, so the one CURRENT does not name goes away.
+            if self.read_current_file().ok() != Some(manifest_id) {
+                if let Err(e) = self.options.env.remove(&manifest_path) {
+                    log::warn!("failed to delete {}: {:?}", manifest_path.display(), e);
+                }
+            }
+            return Err(e);
+        }
+
+        version.manifest_id = manifest_id;
+        self.manifest_file = Some(file);
+        self.manifest_file_id = manifest_id;
+        self.manifest_size = record.len() as u64;
+        self.current = Arc::new(version);
+        Ok(())
+    }
 
+    /// Point CURRENT to `manifest_id`. The content goes to a temp file
+    /// first which is then renamed over CURRENT, so a crash in between
+    /// leaves either the old or the new CURRENT in place.
     fn write_current_file(&self, manifest_id: FileId) -> DBResult<()> {
         let manifest_file = FileType::Manifest.get_filename(manifest_id);
         let contents_to_write = manifest_file.to_str().unwrap();
+        let tmp_filename = FileType::Temp.get_full_filepath(self.dbpath.clone(), manifest_id);
         let current_filename =
-            FileType::Manifest.get_full_filepath(self.dbpath.clone(), 0 /* not used */);
-        let mut c = std::fs::File::options()
-            .create(true)
-            .truncate(true)
-            .write(true)
-            .open(current_filename)
-            .map_err(|e| from_io_error(e))?;
-
-        c.write_all(contents_to_write.as_bytes())
-            .map_err(|e| crate::errors::from_io_error(e))?;
-        c.sync_all().map_err(|e| from_io_error(e))?;
-        Ok(())
+            FileType::Current.get_full_filepath(self.dbpath.clone(), 0 /* not used */);
+
+        let env = self.options.env.as_ref();
+        write_file_atomic(
+            env,
+            &tmp_filename,
+            &current_filename,
+            contents_to_write.as_bytes(),
+        )
+        .and_then(|_| env.sync_dir(&self.dbpath))
+        .map_err(from_io_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    use super::{encode_manifest_record, Version, VersionEdit, VersionSet};
+    use crate::env::{read_file, Env, EnvFile, MemEnv, OpenMode};
+    use crate::errors::ErrorKind;
+    use crate::filename::FileType;
+    use crate::options::Options;
+
+    /// A db directory in a fresh in-memory env.
+    fn prepare_db() -> (PathBuf, Arc<Options>) {
+        let env = MemEnv::new();
+        let path = PathBuf::from("/db");
+        env.create_dir_all(&path).unwrap();
+        let opts = Options {
+            env: Arc::new(env),
+            ..Default::default()
+        };
+        (path, Arc::new(opts))
+    }
+
+    #[test]
+    fn test_version_edit_encode_decode() {
+        let edit = VersionEdit {
+            new_active_file: Some(7),
+            need_freeze: Some(6),
+            compact_input_imm: Some(vec![1, 2, 3]),
+            compact_output_imm: Some(vec![]),
+            next_logfile_id: Some(9),
+            last_sequence: Some(1000),
+        };
+        assert_eq!(VersionEdit::decode(&edit.encode()).unwrap(), edit);
+        let edit = VersionEdit::default();
+        assert_eq!(VersionEdit::decode(&edit.encode()).unwrap(), edit);
+        assert!(VersionEdit::decode(&[1, 0, 0]).is_err());
+        assert!(VersionEdit::decode(&[42]).is_err());
+    }
+
+    #[test]
+    fn test_bad_version_edits() {
+        let zero_next_id = VersionEdit {
+            next_logfile_id: Some(0),
+            ..Default::default()
+        };
+        let e = VersionEdit::decode(&zero_next_id.encode()).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::Corruption);
+        let version = Version {
+            mut_id: 3,
+            ..Default::default()
+        };
+        let bad_freeze = VersionEdit {
+            need_freeze: Some(99),
+            ..Default::default()
+        };
+        let e = version.apply(&bad_freeze).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::Corruption);
+
+        // both fail the recovery instead of panicking
+        let (path, opts) = prepare_db();
+        let mut versions = VersionSet::new(path.clone(), opts.clone());
+        versions.recovery(false).unwrap();
+        rotate(&mut versions, 1);
+        let manifest =
+            FileType::Manifest.get_full_filepath(path.clone(), versions.manifest_file_id);
+        drop(versions);
+        let f = opts.env.open(&manifest, OpenMode::Existing).unwrap();
+        let size = f.size().unwrap();
+        for edit in [zero_next_id, bad_freeze] {
+            f.set_len(size).unwrap();
+            f.write_at(&encode_manifest_record(&edit.encode()), size)
+                .unwrap();
+            let mut versions = VersionSet::new(path.clone(), opts.clone());
+            let e = versions.recovery(false).unwrap_err();
+            assert_eq!(e.kind(), ErrorKind::Corruption);
+        }
+    }
+
+    /// Rotate the active file `n` times starting from `versions`' current one.
+    fn rotate(versions: &mut VersionSet, n: usize) {
+        for _ in 0..n {
+            let current = versions.current();
+            let mut edit = VersionEdit {
+                new_active_file: Some(versions.new_logfile_id()),
+                need_freeze: Some(current.mut_id).filter(|x| *x != 0),
+                ..Default::default()
+            };
+            versions.log_and_apply(&mut edit).unwrap();
+        }
+    }
+
+    fn without_manifest(v: &Version) -> Version {
+        Version {
+            manifest_id: 0,
+            ..v.clone()
+        }
+    }
+
+    #[test]
+    fn test_log_and_apply_recovery() {
+        let (path, opts) = prepare_db();
+        let mut versions = VersionSet::new(path.clone(), opts.clone());
+        versions.recovery(false).unwrap();
+        rotate(&mut versions, 4);
+        let mut edit = VersionEdit {
+            compact_input_imm: Some(vec![2, 3]),
+            compact_output_imm: Some(vec![versions.new_logfile_id()]),
+            ..Default::default()
+        };
+        versions.log_and_apply(&mut edit).unwrap();
+        let expect = versions.current();
+        assert_eq!(expect.mut_id, 5);
+        assert_eq!(expect.imm_ids, vec![4, 6]);
+        let next_id = versions.new_logfile_id();
+        drop(versions);
+
+        let mut versions = VersionSet::new(path.clone(), opts);
+        versions.recovery(false).unwrap();
+        assert_eq!(*versions.current(), *expect);
+        assert!(versions.new_logfile_id() >= next_id);
+    }
+
+    #[test]
+    fn test_manifest_roll() {
+        let (path, opts) = prepare_db();
+        let small_manifest = Arc::new(Options {
+            max_manifest_file_size: 128,
+            ..(*opts).clone()
+        });
+        let mut versions = VersionSet::new(path.clone(), small_manifest.clone());
+        versions.recovery(false).unwrap();
+        let first_manifest = versions.current().manifest_id;
+        rotate(&mut versions, 20);
+        let expect = versions.current();
+        assert_ne!(expect.manifest_id, first_manifest);
+        assert_eq!(expect.imm_ids.len(), 19);
+        drop(versions);
+
+        let mut versions = VersionSet::new(path.clone(), small_manifest.clone());
+        versions.recovery(false).unwrap();
+        assert_eq!(
+            without_manifest(&versions.current()),
+            without_manifest(&expect)
+        );
+        let used: Vec<_> = versions.current().live_file_ids();
+        let id = versions.new_logfile_id();
+        assert!(used.iter().all(|x| *x < id));
+    }
+
+    #[test]
+    fn test_manifest_torn_tail() {
+        let (path, opts) = prepare_db();
+        let mut versions = VersionSet::new(path.clone(), opts.clone());
+        versions.recovery(false).unwrap();
+        rotate(&mut versions, 3);
+        let expect = versions.current();
+        let manifest =
+            crate::filename::FileType::Manifest.get_full_filepath(path.clone(), expect.manifest_id);
+        drop(versions);
+        let f = opts.env.open(&manifest, OpenMode::Existing).unwrap();
+        f.write_at(&[0, 0, 0, 10, 1, 2], f.size().unwrap()).unwrap();
+
+        let mut versions = VersionSet::new(path.clone(), opts.clone());
+        versions.recovery(false).unwrap();
+        assert_eq!(
+            without_manifest(&versions.current()),
+            without_manifest(&expect)
+        );
+        rotate(&mut versions, 1);
+        let expect = versions.current();
+        drop(versions);
+
+        let mut versions = VersionSet::new(path, opts);
+        versions.recovery(false).unwrap();
+        assert_eq!(*versions.current(), *expect);
+    }
+
+    /// A manifest whose writes fail.
+    struct BrokenFile;
+
+    impl EnvFile for BrokenFile {
+        fn read_at(&self, _: &mut [u8], _: u64) -> io::Result<usize> {
+            Ok(0)
+        }
+
+        fn write_at(&self, buf: &[u8], _: u64) -> io::Result<usize> {
+            Ok(buf.len() / 2)
+        }
+
+        fn sync(&self) -> io::Result<()> {
+            Err(io::Error::other("sync failed"))
+        }
+
+        fn set_len(&self, _: u64) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn size(&self) -> io::Result<u64> {
+            Ok(0)
+        }
+    }
+
+    #[test]
+    fn test_failed_edit_rolls_manifest() {
+        let (path, opts) = prepare_db();
+        let mut versions = VersionSet::new(path.clone(), opts.clone());
+        versions.recovery(false).unwrap();
+        rotate(&mut versions, 2);
+        let before = versions.current();
+        versions.manifest_file = Some(Box::new(BrokenFile));
+        let mut edit = VersionEdit {
+            new_active_file: Some(versions.new_logfile_id()),
+            need_freeze: Some(before.mut_id),
+            ..Default::default()
+        };
+        assert!(versions.log_and_apply(&mut edit).is_err());
+        assert_eq!(versions.current(), before);
+
+        rotate(&mut versions, 1);
+        let expect = versions.current();
+        assert_ne!(expect.manifest_id, before.manifest_id);
+        drop(versions);
+        let mut versions = VersionSet::new(path, opts);
+        versions.recovery(false).unwrap();
+        assert_eq!(*versions.current(), *expect);
+    }
+
+    #[test]
+    fn test_missing_manifest() {
+        let (path, opts) = prepare_db();
+        let env = opts.env.clone();
+        let small_manifest = Arc::new(Options {
+            max_manifest_file_size: 128,
+            ..(*opts).clone()
+        });
+        let mut versions = VersionSet::new(path.clone(), small_manifest.clone());
+        versions.recovery(false).unwrap();
+        rotate(&mut versions, 10);
+        let expect = versions.current();
+        drop(versions);
+        let current = read_file(
+            env.as_ref(),
+            &FileType::Current.get_full_filepath(path.clone(), 0),
+        );
+        assert_eq!(
+            current.unwrap(),
+            FileType::Manifest
+                .get_filename(expect.manifest_id)
+                .to_str()
+                .unwrap()
+                .as_bytes()
+        );
+        assert!(!env.exists(&FileType::Temp.get_full_filepath(path.clone(), expect.manifest_id)));
+
+        // CURRENT points to nothing
+        let manifest = FileType::Manifest.get_full_filepath(path.clone(), expect.manifest_id);
+        let backup = path.join("manifest.bak");
+        env.rename(&manifest, &backup).unwrap();
+        let mut versions = VersionSet::new(path.clone(), small_manifest.clone());
+        match versions.recovery(false) {
+            Err(crate::errors::DBError::Corruption { msg, .. }) => assert!(msg.contains("missing")),
+            r => panic!("unexpected {:?}", r.map(|_| ())),
+        }
+
+        // the newest manifest left is an older state of the db
+        let opts = Arc::new(Options {
+            fallback_to_newest_manifest: true,
+            ..(*small_manifest).clone()
+        });
+        let mut versions = VersionSet::new(path.clone(), opts.clone());
+        versions.recovery(false).unwrap();
+        let fallback = versions.current();
+        assert!(versions.used_fallback());
+        assert!(fallback.imm_ids.len() < expect.imm_ids.len());
+        drop(versions);
+
+        // CURRENT was rewritten to the fallback one
+        let mut versions = VersionSet::new(path.clone(), small_manifest);
+        versions.recovery(false).unwrap();
+        assert_eq!(*versions.current(), *fallback);
     }
 }
@@ -1,8 +1,9 @@
-use crate::errors::DBResult;
+use std::time::Duration;
+
 use crate::model::OpType;
-use crate::model::OwnedEntry;
-use crate::model::RefEntry;
+use crate::model::{expire_time, BatchHeader, OwnedEntry, BATCH_HEADER_SIZE, ENTRY_HEADER_SIZE};
 
+#[derive(Default, Clone)]
 pub struct WriteBatch {
     rep: Vec<OwnedEntry>,
 }
@@ -17,7 +18,18 @@ impl WriteBatch {
             op_type: OpType::Put,
             key: key.to_vec(),
             value: Some(value.to_vec()),
-            ts: Some(0),
+            ..Default::default()
+        })
+    }
+
+    /// Put a key which is gone once `ttl` has passed since this call.
+    pub fn put_with_ttl(&mut self, key: &[u8], value: &[u8], ttl: Duration) {
+        self.rep.push(OwnedEntry {
+            op_type: OpType::Put,
+            key: key.to_vec(),
+            value: Some(value.to_vec()),
+            expire_at: expire_time(ttl),
+            ..Default::default()
         })
     }
 
@@ -26,21 +38,67 @@ impl WriteBatch {
             op_type: OpType::Del,
             key: key.to_vec(),
             value: None,
-            ts: Some(0),
+            ..Default::default()
         })
     }
 
-    pub(crate) fn consume_by<F, OUTPUT>(&self, f: F) -> DBResult<Vec<OUTPUT>>
+    pub fn len(&self) -> usize {
+        self.rep.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rep.is_empty()
+    }
+
+    /// The bytes the batch takes in the log.
+    pub(crate) fn encoded_size(&self) -> usize {
+        let records: usize = self
+            .rep
+            .iter()
+            .map(|x| ENTRY_HEADER_SIZE + x.key.len() + 1 + x.value.as_ref().map_or(0, |v| v.len()))
+            .sum();
+        if self.rep.len() > 1 {
+            BATCH_HEADER_SIZE + records
+        } else {
+            records
+        }
+    }
+
+    /// Encode the whole batch into one buffer, the records get sequences
+    /// from `first_seq` on and the write time `ts`. A batch of more than
+    /// one record is framed by a `BatchHeader`, so that recovery applies
+    /// all of it or none. `f` is called with each record, its sequence,
+    /// and its offset and length in the buffer.
+    pub(crate) fn consume_by<F, OUTPUT>(
+        &self,
+        first_seq: u64,
+        ts: u64,
+        mut f: F,
+    ) -> (Vec<u8>, Vec<OUTPUT>)
     where
-        F: Fn(&OwnedEntry) -> DBResult<OUTPUT>,
+        F: FnMut(&OwnedEntry, u64, u64, u64) -> OUTPUT,
     {
-        let mut vec = Vec::new();
-        for x in &self.rep {
-            match f(x) {
-                Ok(o) => vec.push(o),
-                Err(e) => return Err(e),
-            }
+        let framed = self.rep.len() > 1;
+        let header_size = if framed { BATCH_HEADER_SIZE as u64 } else { 0 };
+        let mut body = Vec::with_capacity(self.encoded_size());
+        let mut outputs = Vec::with_capacity(self.rep.len());
+        for (i, x) in self.rep.iter().enumerate() {
+            let start = body.len();
+            let seq = first_seq + i as u64;
+            let mut entry = x.as_ref_entry();
+            entry.seq = seq;
+            entry.ts = Some(ts);
+            entry.encode_to(&mut body);
+            let length = (body.len() - start) as u64;
+            outputs.push(f(x, seq, header_size + start as u64, length));
         }
-        Ok(vec)
+        if !framed {
+            return (body, outputs);
+        }
+
+        let mut data = Vec::with_capacity(BATCH_HEADER_SIZE + body.len());
+        BatchHeader::new(first_seq, self.rep.len() as u32, &body).encode_to(&mut data);
+        data.extend_from_slice(&body);
+        (data, outputs)
     }
 }